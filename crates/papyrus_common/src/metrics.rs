@@ -21,3 +21,6 @@ pub const PAPYRUS_BASE_LAYER_MARKER: &str = "papyrus_base_layer_marker";
 /// The latency, in seconds, between a block timestamp (as state in its header) and the time the
 /// node stores the header.
 pub const PAPYRUS_HEADER_LATENCY_SEC: &str = "papyrus_header_latency";
+
+/// The number of network events that were dropped because the queue of pending events was full.
+pub const PAPYRUS_NETWORK_DROPPED_EVENTS: &str = "papyrus_network_dropped_events";
@@ -38,7 +38,13 @@ async fn main() {
             session_timeout: Duration::from_secs(10),
             idle_connection_timeout: Duration::from_secs(args.idle_connection_timeout),
             header_buffer_size: 100000,
+            db_executor_concurrency: 100,
+            max_blocks_per_query: 10000,
+            send_response_summary: false,
+            max_inbound_sessions: 1000,
+            max_pending_events: 10000,
             peer: None,
+            record_queries_to: None,
         },
         storage_reader,
     );
@@ -13,6 +13,10 @@ use papyrus_network::streamed_bytes::{
     InboundSessionId,
     OutboundSessionId,
     SessionId,
+    DEFAULT_DIAL_TIMEOUT,
+    DEFAULT_MAX_CONCURRENT_DIALS,
+    DEFAULT_MAX_FRAME_BYTES,
+    DEFAULT_MAX_INFLIGHT_BYTES,
 };
 
 const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/papyrus/bench/1");
@@ -98,10 +102,7 @@ fn create_outbound_sessions_if_all_peers_connected(
         for peer_id in peers_pending_outbound_session {
             for _ in 0..args.num_queries_per_connection {
                 let outbound_session_id =
-                    swarm.behaviour_mut().send_query(vec![], *peer_id, PROTOCOL_NAME).expect(
-                        "There's no connection to a peer immediately after we got a \
-                         ConnectionEstablished event",
-                    );
+                    swarm.behaviour_mut().send_query(vec![], *peer_id, PROTOCOL_NAME);
                 outbound_session_measurements
                     .insert(outbound_session_id, OutboundSessionMeasurement::new());
             }
@@ -223,6 +224,13 @@ async fn main() {
     let config = Config {
         session_timeout: Duration::from_secs(3600),
         supported_inbound_protocols: vec![PROTOCOL_NAME],
+        max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+        max_inflight_bytes: DEFAULT_MAX_INFLIGHT_BYTES,
+        dial_timeout: DEFAULT_DIAL_TIMEOUT,
+        max_concurrent_dials: DEFAULT_MAX_CONCURRENT_DIALS,
+        max_pending_events: None,
+        pending_events_overflow_policy: Default::default(),
+        compress_data: false,
     };
     let mut swarm = build_swarm(
         vec![args.listen_address.clone()],
@@ -1,3 +1,6 @@
+#[cfg(test)]
+mod test;
+
 use std::str::FromStr;
 use std::time::Duration;
 
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::swarm::SwarmEvent;
+use tokio::time::Instant;
+
+use super::{build_swarm, dial};
+use crate::streamed_bytes::behaviour::Behaviour;
+use crate::streamed_bytes::Config;
+
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_millis(500);
+const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn connection_without_sessions_is_closed_after_idle_timeout() {
+    let mut listener = build_swarm(
+        vec!["/ip4/127.0.0.1/tcp/0".to_string()],
+        IDLE_CONNECTION_TIMEOUT,
+        Behaviour::new(Config::get_test_config()),
+    );
+    let mut dialer =
+        build_swarm(vec![], IDLE_CONNECTION_TIMEOUT, Behaviour::new(Config::get_test_config()));
+
+    let listen_address = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = listener.select_next_some().await {
+            break address;
+        }
+    };
+    dial(&mut dialer, &listen_address.to_string());
+
+    let connection_lifetime = tokio::time::timeout(TEST_TIMEOUT, async {
+        let mut connection_established_at = None;
+        loop {
+            let event = tokio::select! {
+                event = dialer.select_next_some() => event,
+                _ = listener.select_next_some() => continue,
+            };
+            match event {
+                SwarmEvent::ConnectionEstablished { .. } => {
+                    connection_established_at = Some(Instant::now());
+                }
+                SwarmEvent::ConnectionClosed { .. } => {
+                    break connection_established_at
+                        .expect("The connection was closed before it was established")
+                        .elapsed();
+                }
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("The idle connection wasn't closed");
+    assert!(connection_lifetime >= IDLE_CONNECTION_TIMEOUT);
+}
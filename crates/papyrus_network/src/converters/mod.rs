@@ -6,32 +6,109 @@ mod test;
 use std::collections::HashMap;
 
 use futures::channel::mpsc::{Receiver, Sender};
+use futures::future::poll_fn;
 use futures::StreamExt;
 use prost::Message;
 
+use self::protobuf_conversion::{ProtobufBlockHeaderResponseToDataError, ProtobufConversionError};
+use crate::db_executor::Data;
 use crate::protobuf_messages::protobuf::{self};
-use crate::{Protocol, ResponseReceivers};
+use crate::{
+    DataType,
+    InternalQuery,
+    Protocol,
+    RejectionReason,
+    ResponseReceivers,
+    SessionEvent,
+    SignedBlockHeader,
+};
 
 impl ResponseReceivers {
-    pub(crate) fn new(mut protocol_to_receiver_map: HashMap<Protocol, Receiver<Vec<u8>>>) -> Self {
+    pub(crate) fn new(
+        mut protocol_to_receiver_map: HashMap<Protocol, Receiver<Option<SignedBlockHeader>>>,
+        session_events_receiver: Receiver<SessionEvent>,
+    ) -> Self {
         let signed_headers_receiver = protocol_to_receiver_map
             .remove(&Protocol::SignedBlockHeader)
             .expect("SignedBlockHeader receiver not found")
-            .map(|data_bytes| {
-                protobuf::BlockHeadersResponse::decode(&data_bytes[..])
-                    .expect("failed to decode protobuf SignedBlockHeader")
-                    .try_into()
-                    .expect("failed to convert SignedBlockHeader")
-            })
             .boxed();
-        Self { signed_headers_receiver }
+        Self { signed_headers_receiver, session_events_receiver: session_events_receiver.boxed() }
     }
 }
 
+/// The type of the responses that are sent on an inbound session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ResponseType {
+    /// The data of each of the queried blocks.
+    BlockData(DataType),
+    /// The highest block in the storage.
+    ChainTip,
+}
+
+/// Decode a query that another peer sent on a protocol with the given response type. Chain tip
+/// requests don't contain a query, so None is returned for them.
+pub(crate) fn decode_inbound_query(
+    response_type: ResponseType,
+    query_bytes: &[u8],
+) -> Result<Option<InternalQuery>, ProtobufConversionError> {
+    let query = match response_type {
+        ResponseType::BlockData(DataType::SignedBlockHeader) => {
+            Some(protobuf::BlockHeadersRequest::decode(query_bytes)?.try_into()?)
+        }
+        ResponseType::BlockData(DataType::StateDiff) => {
+            Some(protobuf::StateDiffsRequest::decode(query_bytes)?.try_into()?)
+        }
+        ResponseType::ChainTip => {
+            protobuf::ChainTipRequest::decode(query_bytes)?;
+            None
+        }
+    };
+    Ok(query)
+}
+
+/// Encode data as a response message of a protocol with the given response type.
+pub(crate) fn encode_response(
+    response_type: ResponseType,
+    data: Data,
+) -> Result<Vec<u8>, ProtobufBlockHeaderResponseToDataError> {
+    let data_bytes = match response_type {
+        ResponseType::BlockData(DataType::SignedBlockHeader) => {
+            protobuf::BlockHeadersResponse::try_from(data)?.encode_to_vec()
+        }
+        ResponseType::BlockData(DataType::StateDiff) => {
+            protobuf::StateDiffsResponse::try_from(data)?.encode_to_vec()
+        }
+        ResponseType::ChainTip => protobuf::ChainTipResponse::try_from(data)?.encode_to_vec(),
+    };
+    Ok(data_bytes)
+}
+
+/// A block headers response that another peer sent on a session of a query we sent it.
+#[derive(Debug)]
+pub(crate) enum ReceivedHeadersResponse {
+    /// A header of the queried blocks, or None once the peer finished sending them.
+    Header(Option<SignedBlockHeader>),
+    /// The peer refused to serve the query.
+    Rejection(RejectionReason),
+}
+
+/// Decode a block headers response that another peer sent.
+pub(crate) fn decode_headers_response(
+    data_bytes: &[u8],
+) -> Result<ReceivedHeadersResponse, ProtobufConversionError> {
+    let response = protobuf::BlockHeadersResponse::decode(data_bytes)?;
+    if let Some(protobuf::block_headers_response::HeaderMessage::Rejection(rejection)) =
+        response.header_message
+    {
+        return Ok(ReceivedHeadersResponse::Rejection(rejection.try_into()?));
+    }
+    Ok(ReceivedHeadersResponse::Header(response.try_into()?))
+}
+
 #[allow(unused)]
 pub(crate) struct Router {
-    pub protocol_to_sender_map: HashMap<Protocol, Sender<Vec<u8>>>,
-    pub protocol_to_receiver_map: Option<HashMap<Protocol, Receiver<Vec<u8>>>>,
+    pub protocol_to_sender_map: HashMap<Protocol, Sender<Option<SignedBlockHeader>>>,
+    pub protocol_to_receiver_map: Option<HashMap<Protocol, Receiver<Option<SignedBlockHeader>>>>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -39,7 +116,7 @@ pub enum RouterError {
     #[error("This Router doesn't support protocol {protocol:?}")]
     NoSenderForProtocol { protocol: Protocol },
     #[error(transparent)]
-    TrySendError(#[from] futures::channel::mpsc::TrySendError<Vec<u8>>),
+    TrySendError(#[from] futures::channel::mpsc::TrySendError<Option<SignedBlockHeader>>),
 }
 
 impl Router {
@@ -54,11 +131,24 @@ impl Router {
         Self { protocol_to_sender_map, protocol_to_receiver_map: Some(protocol_to_receiver_map) }
     }
 
-    pub fn get_recievers(&mut self) -> HashMap<Protocol, Receiver<Vec<u8>>> {
+    pub fn get_recievers(&mut self) -> HashMap<Protocol, Receiver<Option<SignedBlockHeader>>> {
         self.protocol_to_receiver_map.take().unwrap_or_default()
     }
 
-    pub fn try_send(&mut self, protocol: Protocol, data: Vec<u8>) -> Result<(), RouterError> {
+    /// Wait until every sender has room for a message, so that the next `try_send` on any
+    /// protocol doesn't fail for the channel being full.
+    pub async fn ready(&mut self) {
+        for sender in self.protocol_to_sender_map.values_mut() {
+            // A disconnected receiver is reported by `try_send`.
+            let _ = poll_fn(|cx| sender.poll_ready(cx)).await;
+        }
+    }
+
+    pub fn try_send(
+        &mut self,
+        protocol: Protocol,
+        data: Option<SignedBlockHeader>,
+    ) -> Result<(), RouterError> {
         self.protocol_to_sender_map
             .get_mut(&protocol)
             .ok_or(RouterError::NoSenderForProtocol { protocol })
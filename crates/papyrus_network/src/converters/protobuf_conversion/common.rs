@@ -1,7 +1,10 @@
+use starknet_api::block::{BlockHash, BlockNumber};
 use starknet_api::data_availability::L1DataAvailabilityMode;
 
 use super::ProtobufConversionError;
+use crate::db_executor::ResponseSummary;
 use crate::protobuf_messages::protobuf;
+use crate::{BlockHashOrNumber, Direction, InternalQuery, RejectionReason};
 
 #[cfg(test)]
 pub const PATRICIA_HEIGHT: u32 = 251;
@@ -111,6 +114,102 @@ impl TryFrom<protobuf::Address> for starknet_api::core::ContractAddress {
     }
 }
 
+impl From<ResponseSummary> for protobuf::ResponseSummary {
+    fn from(value: ResponseSummary) -> Self {
+        Self {
+            num_blocks: value.num_blocks,
+            highest_block: value.highest_block.map_or(0, |block_number| block_number.0),
+            total_bytes: value.total_bytes,
+        }
+    }
+}
+
+impl From<protobuf::ResponseSummary> for ResponseSummary {
+    fn from(value: protobuf::ResponseSummary) -> Self {
+        Self {
+            num_blocks: value.num_blocks,
+            highest_block: (value.num_blocks > 0).then_some(BlockNumber(value.highest_block)),
+            total_bytes: value.total_bytes,
+        }
+    }
+}
+
+impl From<RejectionReason> for protobuf::Rejection {
+    fn from(value: RejectionReason) -> Self {
+        Self {
+            reason: match value {
+                RejectionReason::Filtered => 0,
+                RejectionReason::TooManySessions => 1,
+            },
+        }
+    }
+}
+
+impl TryFrom<protobuf::Rejection> for RejectionReason {
+    type Error = ProtobufConversionError;
+    fn try_from(value: protobuf::Rejection) -> Result<Self, Self::Error> {
+        match value.reason {
+            0 => Ok(RejectionReason::Filtered),
+            1 => Ok(RejectionReason::TooManySessions),
+            reason => Err(ProtobufConversionError::OutOfRangeValue {
+                type_description: "RejectionReason",
+                value_as_str: format!("{reason}"),
+            }),
+        }
+    }
+}
+
+impl TryFrom<protobuf::Iteration> for InternalQuery {
+    type Error = ProtobufConversionError;
+    fn try_from(value: protobuf::Iteration) -> Result<Self, Self::Error> {
+        let start = value.start.ok_or(ProtobufConversionError::MissingField {
+            field_description: "Iteration::start",
+        })?;
+        let start_block = match start {
+            protobuf::iteration::Start::BlockNumber(block_number) => {
+                BlockHashOrNumber::Number(BlockNumber(block_number))
+            }
+            protobuf::iteration::Start::Header(protobuf_hash) => {
+                BlockHashOrNumber::Hash(BlockHash(protobuf_hash.try_into()?))
+            }
+        };
+        let direction = match value.direction {
+            0 => Direction::Forward,
+            1 => Direction::Backward,
+            direction => {
+                return Err(ProtobufConversionError::OutOfRangeValue {
+                    type_description: "Direction",
+                    value_as_str: format!("{direction}"),
+                });
+            }
+        };
+        let limit = value.limit;
+        let step = value.step;
+        Ok(Self { start_block, direction, limit, step })
+    }
+}
+
+impl From<InternalQuery> for protobuf::Iteration {
+    fn from(value: InternalQuery) -> Self {
+        protobuf::Iteration {
+            direction: match value.direction {
+                Direction::Forward => 0,
+                Direction::Backward => 1,
+            },
+            limit: value.limit,
+            step: value.step,
+            start: Some(match value.start_block {
+                BlockHashOrNumber::Number(block_number) => {
+                    protobuf::iteration::Start::BlockNumber(block_number.0)
+                }
+                BlockHashOrNumber::Hash(block_hash) => {
+                    protobuf::iteration::Start::Header(block_hash.into())
+                }
+            }),
+        }
+    }
+}
+
 pub(super) fn enum_int_to_l1_data_availability_mode(
     value: i32,
 ) -> Result<L1DataAvailabilityMode, ProtobufConversionError> {
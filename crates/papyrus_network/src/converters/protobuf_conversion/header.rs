@@ -19,7 +19,7 @@ use super::common::{enum_int_to_l1_data_availability_mode, l1_data_availability_
 use super::{ProtobufBlockHeaderResponseToDataError, ProtobufConversionError};
 use crate::db_executor::Data;
 use crate::protobuf_messages::protobuf;
-use crate::{BlockHashOrNumber, Direction, InternalQuery, Query, SignedBlockHeader};
+use crate::{Direction, InternalQuery, Query, SignedBlockHeader};
 
 impl TryFrom<protobuf::BlockHeadersResponse> for Option<SignedBlockHeader> {
     type Error = ProtobufConversionError;
@@ -28,7 +28,9 @@ impl TryFrom<protobuf::BlockHeadersResponse> for Option<SignedBlockHeader> {
             Some(protobuf::block_headers_response::HeaderMessage::Header(header)) => {
                 Ok(Some(header.try_into()?))
             }
-            Some(protobuf::block_headers_response::HeaderMessage::Fin(_)) => Ok(None),
+            // No headers arrive after Fin or a rejection.
+            Some(protobuf::block_headers_response::HeaderMessage::Fin(_))
+            | Some(protobuf::block_headers_response::HeaderMessage::Rejection(_)) => Ok(None),
             None => Err(ProtobufConversionError::MissingField {
                 field_description: "BlockHeadersResponse::header_message",
             }),
@@ -272,7 +274,17 @@ impl TryFrom<Data> for protobuf::BlockHeadersResponse {
             }
             Data::Fin => Ok(protobuf::BlockHeadersResponse {
                 header_message: Some(protobuf::block_headers_response::HeaderMessage::Fin(
-                    protobuf::Fin {},
+                    protobuf::Fin { summary: None },
+                )),
+            }),
+            Data::FinWithSummary { summary } => Ok(protobuf::BlockHeadersResponse {
+                header_message: Some(protobuf::block_headers_response::HeaderMessage::Fin(
+                    protobuf::Fin { summary: Some(summary.into()) },
+                )),
+            }),
+            Data::Rejection { reason } => Ok(protobuf::BlockHeadersResponse {
+                header_message: Some(protobuf::block_headers_response::HeaderMessage::Rejection(
+                    reason.into(),
                 )),
             }),
             Data::StateDiff { .. } => {
@@ -281,6 +293,12 @@ impl TryFrom<Data> for protobuf::BlockHeadersResponse {
                     type_description: "BlockHeadersResponse".to_string(),
                 })
             }
+            Data::ChainTip { .. } => {
+                Err(ProtobufBlockHeaderResponseToDataError::UnsupportedDataType {
+                    data_type: "ChainTip".to_string(),
+                    type_description: "BlockHeadersResponse".to_string(),
+                })
+            }
         }
     }
 }
@@ -297,7 +315,15 @@ impl TryFrom<protobuf::BlockHeadersResponse> for Data {
                     signatures: signed_block_header.signatures,
                 })
             }
-            Some(protobuf::block_headers_response::HeaderMessage::Fin(_)) => Ok(Data::Fin),
+            Some(protobuf::block_headers_response::HeaderMessage::Fin(protobuf::Fin {
+                summary: None,
+            })) => Ok(Data::Fin),
+            Some(protobuf::block_headers_response::HeaderMessage::Fin(protobuf::Fin {
+                summary: Some(summary),
+            })) => Ok(Data::FinWithSummary { summary: summary.into() }),
+            Some(protobuf::block_headers_response::HeaderMessage::Rejection(rejection)) => {
+                Ok(Data::Rejection { reason: rejection.try_into()? })
+            }
             None => Err(ProtobufConversionError::MissingField {
                 field_description: "BlockHeadersResponse::header_message",
             }),
@@ -305,36 +331,93 @@ impl TryFrom<protobuf::BlockHeadersResponse> for Data {
     }
 }
 
-impl TryFrom<protobuf::BlockHeadersRequest> for InternalQuery {
-    type Error = ProtobufConversionError;
-    fn try_from(value: protobuf::BlockHeadersRequest) -> Result<Self, Self::Error> {
-        let value = value.iteration.ok_or(ProtobufConversionError::MissingField {
-            field_description: "BlockHeadersRequest::iteration",
-        })?;
-        let start = value.start.ok_or(ProtobufConversionError::MissingField {
-            field_description: "Iteration::start",
-        })?;
-        let start_block = match start {
-            protobuf::iteration::Start::BlockNumber(block_number) => {
-                BlockHashOrNumber::Number(BlockNumber(block_number))
+impl TryFrom<Data> for protobuf::ChainTipResponse {
+    type Error = ProtobufBlockHeaderResponseToDataError;
+
+    fn try_from(data: Data) -> Result<Self, Self::Error> {
+        let chain_tip_message = match data {
+            Data::ChainTip { block_number, block_hash } => {
+                protobuf::chain_tip_response::ChainTipMessage::Tip(protobuf::BlockId {
+                    number: block_number.0,
+                    header: Some(block_hash.into()),
+                })
             }
-            protobuf::iteration::Start::Header(protobuf_hash) => {
-                BlockHashOrNumber::Hash(BlockHash(protobuf_hash.try_into()?))
+            Data::Fin => {
+                protobuf::chain_tip_response::ChainTipMessage::Fin(protobuf::Fin { summary: None })
             }
-        };
-        let direction = match value.direction {
-            0 => Direction::Forward,
-            1 => Direction::Backward,
-            direction => {
-                return Err(ProtobufConversionError::OutOfRangeValue {
-                    type_description: "Direction",
-                    value_as_str: format!("{direction}"),
+            Data::FinWithSummary { summary } => {
+                protobuf::chain_tip_response::ChainTipMessage::Fin(protobuf::Fin {
+                    summary: Some(summary.into()),
+                })
+            }
+            Data::Rejection { reason } => {
+                protobuf::chain_tip_response::ChainTipMessage::Rejection(reason.into())
+            }
+            Data::BlockHeaderAndSignature { .. } => {
+                return Err(ProtobufBlockHeaderResponseToDataError::UnsupportedDataType {
+                    data_type: "BlockHeaderAndSignature".to_string(),
+                    type_description: "ChainTipResponse".to_string(),
+                });
+            }
+            Data::StateDiff { .. } => {
+                return Err(ProtobufBlockHeaderResponseToDataError::UnsupportedDataType {
+                    data_type: "StateDiff".to_string(),
+                    type_description: "ChainTipResponse".to_string(),
                 });
             }
         };
-        let limit = value.limit;
-        let step = value.step;
-        Ok(Self { start_block, direction, limit, step })
+        Ok(protobuf::ChainTipResponse { chain_tip_message: Some(chain_tip_message) })
+    }
+}
+
+impl TryFrom<protobuf::ChainTipResponse> for Data {
+    type Error = ProtobufConversionError;
+
+    fn try_from(value: protobuf::ChainTipResponse) -> Result<Self, Self::Error> {
+        match value.chain_tip_message {
+            Some(protobuf::chain_tip_response::ChainTipMessage::Tip(protobuf::BlockId {
+                number,
+                header,
+            })) => {
+                let block_hash = header
+                    .ok_or(ProtobufConversionError::MissingField {
+                        field_description: "BlockID::header",
+                    })?
+                    .try_into()
+                    .map(BlockHash)?;
+                Ok(Data::ChainTip { block_number: BlockNumber(number), block_hash })
+            }
+            Some(protobuf::chain_tip_response::ChainTipMessage::Fin(protobuf::Fin {
+                summary: None,
+            })) => Ok(Data::Fin),
+            Some(protobuf::chain_tip_response::ChainTipMessage::Fin(protobuf::Fin {
+                summary: Some(summary),
+            })) => Ok(Data::FinWithSummary { summary: summary.into() }),
+            Some(protobuf::chain_tip_response::ChainTipMessage::Rejection(rejection)) => {
+                Ok(Data::Rejection { reason: rejection.try_into()? })
+            }
+            None => Err(ProtobufConversionError::MissingField {
+                field_description: "ChainTipResponse::chain_tip_message",
+            }),
+        }
+    }
+}
+
+impl TryFrom<protobuf::BlockHeadersRequest> for InternalQuery {
+    type Error = ProtobufConversionError;
+    fn try_from(value: protobuf::BlockHeadersRequest) -> Result<Self, Self::Error> {
+        value
+            .iteration
+            .ok_or(ProtobufConversionError::MissingField {
+                field_description: "BlockHeadersRequest::iteration",
+            })?
+            .try_into()
+    }
+}
+
+impl From<InternalQuery> for protobuf::BlockHeadersRequest {
+    fn from(value: InternalQuery) -> Self {
+        protobuf::BlockHeadersRequest { iteration: Some(value.into()) }
     }
 }
 
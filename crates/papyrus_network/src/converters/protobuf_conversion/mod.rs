@@ -10,6 +10,8 @@ pub enum ProtobufConversionError {
     MissingField { field_description: &'static str },
     #[error("Type `{type_description}` should be {num_expected} bytes but it got {value:?}.")]
     BytesDataLengthMismatch { type_description: &'static str, num_expected: usize, value: Vec<u8> },
+    #[error(transparent)]
+    DecodeError(#[from] prost::DecodeError),
 }
 
 #[derive(thiserror::Error, Debug)]
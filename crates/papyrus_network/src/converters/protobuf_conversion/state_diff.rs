@@ -3,8 +3,10 @@ use starknet_api::core::{ClassHash, CompiledClassHash, Nonce};
 use starknet_api::hash::StarkFelt;
 use starknet_api::state::{StorageKey, ThinStateDiff};
 
-use super::ProtobufConversionError;
+use super::{ProtobufBlockHeaderResponseToDataError, ProtobufConversionError};
+use crate::db_executor::Data;
 use crate::protobuf_messages::protobuf;
+use crate::InternalQuery;
 
 impl TryFrom<protobuf::StateDiffsResponse> for Option<ThinStateDiff> {
     type Error = ProtobufConversionError;
@@ -16,7 +18,9 @@ impl TryFrom<protobuf::StateDiffsResponse> for Option<ThinStateDiff> {
             Some(protobuf::state_diffs_response::StateDiffMessage::DeclaredClass(
                 declared_class,
             )) => Ok(Some(declared_class.try_into()?)),
-            Some(protobuf::state_diffs_response::StateDiffMessage::Fin(_)) => Ok(None),
+            // No state diffs arrive after Fin or a rejection.
+            Some(protobuf::state_diffs_response::StateDiffMessage::Fin(_))
+            | Some(protobuf::state_diffs_response::StateDiffMessage::Rejection(_)) => Ok(None),
             None => Err(ProtobufConversionError::MissingField {
                 field_description: "StateDiffsResponse::state_diff_message",
             }),
@@ -24,6 +28,64 @@ impl TryFrom<protobuf::StateDiffsResponse> for Option<ThinStateDiff> {
     }
 }
 
+impl TryFrom<Data> for protobuf::StateDiffsResponse {
+    type Error = ProtobufBlockHeaderResponseToDataError;
+
+    fn try_from(data: Data) -> Result<Self, Self::Error> {
+        let state_diff_message = match data {
+            Data::Fin => protobuf::state_diffs_response::StateDiffMessage::Fin(protobuf::Fin {
+                summary: None,
+            }),
+            Data::FinWithSummary { summary } => {
+                protobuf::state_diffs_response::StateDiffMessage::Fin(protobuf::Fin {
+                    summary: Some(summary.into()),
+                })
+            }
+            Data::Rejection { reason } => {
+                protobuf::state_diffs_response::StateDiffMessage::Rejection(reason.into())
+            }
+            // TODO: split the state diff of a block into its contract diffs and declared classes.
+            Data::StateDiff { .. } => {
+                return Err(ProtobufBlockHeaderResponseToDataError::UnsupportedDataType {
+                    data_type: "StateDiff".to_string(),
+                    type_description: "StateDiffsResponse".to_string(),
+                });
+            }
+            Data::BlockHeaderAndSignature { .. } => {
+                return Err(ProtobufBlockHeaderResponseToDataError::UnsupportedDataType {
+                    data_type: "BlockHeaderAndSignature".to_string(),
+                    type_description: "StateDiffsResponse".to_string(),
+                });
+            }
+            Data::ChainTip { .. } => {
+                return Err(ProtobufBlockHeaderResponseToDataError::UnsupportedDataType {
+                    data_type: "ChainTip".to_string(),
+                    type_description: "StateDiffsResponse".to_string(),
+                });
+            }
+        };
+        Ok(protobuf::StateDiffsResponse { state_diff_message: Some(state_diff_message) })
+    }
+}
+
+impl TryFrom<protobuf::StateDiffsRequest> for InternalQuery {
+    type Error = ProtobufConversionError;
+    fn try_from(value: protobuf::StateDiffsRequest) -> Result<Self, Self::Error> {
+        value
+            .iteration
+            .ok_or(ProtobufConversionError::MissingField {
+                field_description: "StateDiffsRequest::iteration",
+            })?
+            .try_into()
+    }
+}
+
+impl From<InternalQuery> for protobuf::StateDiffsRequest {
+    fn from(value: InternalQuery) -> Self {
+        protobuf::StateDiffsRequest { iteration: Some(value.into()) }
+    }
+}
+
 impl TryFrom<protobuf::ContractDiff> for ThinStateDiff {
     type Error = ProtobufConversionError;
     fn try_from(value: protobuf::ContractDiff) -> Result<Self, Self::Error> {
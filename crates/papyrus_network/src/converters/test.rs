@@ -1,8 +1,12 @@
+use assert_matches::assert_matches;
 use prost::Message;
-use starknet_api::block::BlockHeader;
+use starknet_api::block::{BlockHash, BlockHeader, BlockNumber};
+use starknet_api::hash::StarkFelt;
 
+use super::ReceivedHeadersResponse;
 use crate::db_executor::Data;
 use crate::protobuf_messages::protobuf;
+use crate::RejectionReason;
 
 #[test]
 fn block_header_to_protobuf_to_bytes_and_back() {
@@ -22,3 +26,43 @@ fn block_header_to_protobuf_to_bytes_and_back() {
         protobuf::BlockHeadersResponse::decode(&data_bytes[..]).unwrap().try_into().unwrap();
     assert_eq!(res_data, data);
 }
+
+#[test]
+fn chain_tip_to_protobuf_to_bytes_and_back() {
+    let data = Data::ChainTip {
+        block_number: BlockNumber(7),
+        block_hash: BlockHash(StarkFelt::from(7u8)),
+    };
+    let data_bytes = protobuf::ChainTipResponse::try_from(data.clone())
+        .expect("Data::ChainTip should be convertable to protobuf::ChainTipResponse")
+        .encode_to_vec();
+    let res_data: Data =
+        protobuf::ChainTipResponse::decode(&data_bytes[..]).unwrap().try_into().unwrap();
+    assert_eq!(res_data, data);
+}
+
+#[test]
+fn rejection_to_protobuf_to_bytes_and_back() {
+    let data = Data::Rejection { reason: RejectionReason::Filtered };
+    let data_bytes = protobuf::BlockHeadersResponse::try_from(data.clone())
+        .expect("Data::Rejection should be convertable to protobuf::BlockHeadersResponse")
+        .encode_to_vec();
+    let res_data: Data =
+        protobuf::BlockHeadersResponse::decode(&data_bytes[..]).unwrap().try_into().unwrap();
+    assert_eq!(res_data, data);
+    assert_matches!(
+        super::decode_headers_response(&data_bytes),
+        Ok(ReceivedHeadersResponse::Rejection(RejectionReason::Filtered))
+    );
+}
+
+#[test]
+fn chain_tip_rejection_to_protobuf_to_bytes_and_back() {
+    let data = Data::Rejection { reason: RejectionReason::TooManySessions };
+    let data_bytes = protobuf::ChainTipResponse::try_from(data.clone())
+        .expect("Data::Rejection should be convertable to protobuf::ChainTipResponse")
+        .encode_to_vec();
+    let res_data: Data =
+        protobuf::ChainTipResponse::decode(&data_bytes[..]).unwrap().try_into().unwrap();
+    assert_eq!(res_data, data);
+}
@@ -1,21 +1,25 @@
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Poll;
+use std::time::Duration;
 
 use derive_more::Display;
 use futures::channel::mpsc::Sender;
-use futures::future::poll_fn;
+use futures::future::BoxFuture;
 use futures::stream::FuturesUnordered;
-use futures::{Stream, StreamExt};
+use futures::{FutureExt, Stream, StreamExt};
 #[cfg(test)]
 use mockall::automock;
 use papyrus_storage::header::HeaderStorageReader;
 use papyrus_storage::state::StateStorageReader;
-use papyrus_storage::{db, StorageReader, StorageTxn};
-use starknet_api::block::{BlockHeader, BlockNumber, BlockSignature};
+use papyrus_storage::{db, StorageError, StorageReader, StorageResult, StorageTxn};
+use starknet_api::block::{BlockHash, BlockHeader, BlockNumber, BlockSignature};
 use starknet_api::state::ThinStateDiff;
+use tokio::sync::Semaphore;
 use tokio::task::JoinHandle;
+use tracing::{debug, info_span, Instrument};
 
-use crate::{BlockHashOrNumber, DataType, InternalQuery};
+use crate::{BlockHashOrNumber, DataType, InternalQuery, RejectionReason};
 
 #[cfg(test)]
 mod test;
@@ -25,6 +29,25 @@ mod utils;
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Display)]
 pub struct QueryId(pub usize);
 
+/// Allocates unique query ids in increasing order.
+#[derive(Debug, Default)]
+pub struct QueryIdGenerator {
+    next_query_id: usize,
+}
+
+impl QueryIdGenerator {
+    /// Returns the id that the next call to `next_id` will allocate, without allocating it.
+    pub fn peek_next_id(&self) -> QueryId {
+        QueryId(self.next_query_id)
+    }
+
+    pub fn next_id(&mut self) -> QueryId {
+        let query_id = self.peek_next_id();
+        self.next_query_id += 1;
+        query_id
+    }
+}
+
 #[cfg_attr(test, derive(Debug, Clone, PartialEq, Eq, Default))]
 pub enum Data {
     // TODO(shahak): Consider uniting with SignedBlockHeader.
@@ -35,10 +58,37 @@ pub enum Data {
     StateDiff {
         state_diff: ThinStateDiff,
     },
+    /// Marks the end of the data of a query, like `Fin`, and summarizes the data that was sent so
+    /// that the receiver can verify it. Sent instead of `Fin` only if the responder is configured
+    /// to send response summaries.
+    FinWithSummary {
+        summary: ResponseSummary,
+    },
+    /// The highest block in the storage of the responding node.
+    ChainTip {
+        block_number: BlockNumber,
+        block_hash: BlockHash,
+    },
+    /// Sent before `Fin` instead of the data of a query that the responder refuses to serve.
+    Rejection {
+        reason: RejectionReason,
+    },
     #[cfg_attr(test, default)]
     Fin,
 }
 
+/// Summary of the data that was sent as a response to a query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResponseSummary {
+    /// Number of blocks whose data was sent.
+    pub num_blocks: u64,
+    /// The highest block whose data was sent, if any.
+    pub highest_block: Option<BlockNumber>,
+    /// Total size in bytes of the encoded messages that were sent before the summary. Filled by
+    /// the network manager, which encodes the messages.
+    pub total_bytes: u64,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum DBExecutorError {
     #[error("Storage error. Query id: {query_id}, error: {storage_error:?}")]
@@ -47,10 +97,6 @@ pub enum DBExecutorError {
         #[source]
         storage_error: papyrus_storage::StorageError,
     },
-    #[error(
-        "Block number is out of range. Query: {query:?}, counter: {counter}, query_id: {query_id}"
-    )]
-    BlockNumberOutOfRange { query: InternalQuery, counter: u64, query_id: QueryId },
     // TODO: add data type to the error message.
     #[error("Block not found. Block: {block_hash_or_number:?}, query_id: {query_id}")]
     BlockNotFound { block_hash_or_number: BlockHashOrNumber, query_id: QueryId },
@@ -63,6 +109,9 @@ pub enum DBExecutorError {
         "Block {block_number:?} is in the storage but its signature isn't. query_id: {query_id}"
     )]
     SignatureNotFound { block_number: BlockNumber, query_id: QueryId },
+    // The receiver was dropped, e.g. because the session of the query was closed.
+    #[error("The receiver of the query's data was dropped. Query id: {query_id}")]
+    ChannelClosed { query_id: QueryId },
     #[error("Send error. Query id: {query_id}, error: {send_error:?}")]
     SendError {
         query_id: QueryId,
@@ -76,9 +125,9 @@ impl DBExecutorError {
     pub fn query_id(&self) -> Option<QueryId> {
         match self {
             Self::DBInternalError { query_id, .. }
-            | Self::BlockNumberOutOfRange { query_id, .. }
             | Self::BlockNotFound { query_id, .. }
             | Self::SignatureNotFound { query_id, .. }
+            | Self::ChannelClosed { query_id }
             | Self::SendError { query_id, .. } => Some(*query_id),
             Self::JoinError(_) => None,
         }
@@ -89,97 +138,285 @@ impl DBExecutorError {
             Self::JoinError(_) | Self::SignatureNotFound { .. } | Self::SendError { .. }
             // TODO(shahak): Consider returning false for some of the StorageError variants.
             | Self::DBInternalError { .. } => true,
-            Self::BlockNumberOutOfRange { .. } | Self::BlockNotFound { .. } => false,
+            Self::BlockNotFound { .. } | Self::ChannelClosed { .. } => false,
         }
     }
+
+    /// Whether the error means the database was temporarily unable to serve the request (all its
+    /// reader slots were taken or it was busy), in which case opening a new transaction later might
+    /// succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::DBInternalError {
+                storage_error: StorageError::InnerError(db::DbError::Inner(
+                    libmdbx::Error::ReadersFull | libmdbx::Error::Busy
+                )),
+                ..
+            }
+        )
+    }
 }
 
 /// Db executor is a stream of queries. Each result is marks the end of a query fulfillment.
 /// A query can either succeed (and return Ok(QueryId)) or fail (and return Err(DBExecutorError)).
 /// The stream is never exhausted, and it is the responsibility of the user to poll it.
+///
+/// All the blocks of a query are read from a single snapshot of the storage, taken when the query
+/// starts. Blocks written or reverted while the query runs don't affect its results.
 pub trait DBExecutor: Stream<Item = Result<QueryId, DBExecutorError>> + Unpin {
     // TODO: add writer functionality
     fn register_query(
         &mut self,
         query: InternalQuery,
-        data_type: impl FetchBlockDataFromDb + Send + 'static,
+        data_type: Box<dyn FetchBlockDataFromDb + Send>,
         sender: Sender<Data>,
     ) -> QueryId;
+
+    /// Send the highest block that's committed to the storage as a single `Data::ChainTip`. If
+    /// the storage has no blocks, nothing is sent.
+    fn register_chain_tip_query(&mut self, sender: Sender<Data>) -> QueryId;
+
+    /// Stop starting new queries. Queries that are registered while paused start when `resume` is
+    /// called. Queries that already started keep running.
+    fn pause(&mut self);
+
+    /// Start the queries that were registered while paused.
+    fn resume(&mut self);
+
+    /// Pass the data of every block through the given transformer before sending it. Applies only
+    /// to queries that are registered after this call.
+    fn set_data_transformer(&mut self, data_transformer: Arc<dyn DataTransformer>);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DBExecutorConfig {
+    /// Maximal number of queries that read from the storage at the same time. Queries that are
+    /// registered beyond this number wait until one of the running queries finishes. 0 is treated
+    /// as 1, since no query would ever run otherwise.
+    pub concurrency: usize,
+    /// Maximal number of times opening the storage transaction of a query is retried when it fails
+    /// on a transient error (e.g. all the database reader slots are taken) before the query fails.
+    pub max_retries: u32,
+    /// The time to wait before the first retry of opening a storage transaction. The time is
+    /// doubled on each subsequent retry.
+    pub retry_base_delay: Duration,
+    /// Maximal number of blocks that a single query returns. Queries with a higher limit are
+    /// truncated to this number of blocks.
+    pub max_blocks_per_query: u64,
+    /// Whether to end the data of each successful query with a `Data::FinWithSummary` instead of
+    /// leaving it to the caller to end it with a bare `Data::Fin`. Peers that don't know the
+    /// summary read it as a regular Fin.
+    pub send_response_summary: bool,
+}
+
+impl Default for DBExecutorConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 100,
+            max_retries: 3,
+            retry_base_delay: Duration::from_millis(10),
+            max_blocks_per_query: 10000,
+            send_response_summary: false,
+        }
+    }
 }
 
 // TODO: currently this executor returns only block headers and signatures.
-pub struct BlockHeaderDBExecutor {
-    next_query_id: usize,
-    storage_reader: StorageReader,
+pub struct BlockHeaderDBExecutor<Reader: StorageTxnOpener = StorageReader> {
+    query_id_generator: QueryIdGenerator,
+    storage_reader: Reader,
     query_execution_set: FuturesUnordered<JoinHandle<Result<QueryId, DBExecutorError>>>,
+    query_execution_permits: Arc<Semaphore>,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    max_blocks_per_query: u64,
+    send_response_summary: bool,
+    data_transformer: Option<Arc<dyn DataTransformer>>,
+    is_paused: bool,
+    paused_query_executions: Vec<BoxFuture<'static, Result<QueryId, DBExecutorError>>>,
 }
 
-impl BlockHeaderDBExecutor {
+impl<Reader: StorageTxnOpener> BlockHeaderDBExecutor<Reader> {
     #[allow(dead_code)]
-    pub fn new(storage_reader: StorageReader) -> Self {
-        Self { next_query_id: 0, storage_reader, query_execution_set: FuturesUnordered::new() }
+    pub fn new(storage_reader: Reader, config: DBExecutorConfig) -> Self {
+        Self {
+            query_id_generator: QueryIdGenerator::default(),
+            storage_reader,
+            query_execution_set: FuturesUnordered::new(),
+            query_execution_permits: Arc::new(Semaphore::new(config.concurrency.max(1))),
+            max_retries: config.max_retries,
+            retry_base_delay: config.retry_base_delay,
+            max_blocks_per_query: config.max_blocks_per_query,
+            send_response_summary: config.send_response_summary,
+            data_transformer: None,
+            is_paused: false,
+            paused_query_executions: Vec::new(),
+        }
+    }
+
+    /// Returns the id that the next registered query will get.
+    #[cfg(test)]
+    pub fn peek_next_query_id(&self) -> QueryId {
+        self.query_id_generator.peek_next_id()
     }
 }
 
-impl DBExecutor for BlockHeaderDBExecutor {
+impl<Reader: StorageTxnOpener> DBExecutor for BlockHeaderDBExecutor<Reader> {
     fn register_query(
         &mut self,
         query: InternalQuery,
-        data_type: impl FetchBlockDataFromDb + Send + 'static,
+        data_type: Box<dyn FetchBlockDataFromDb + Send>,
         mut sender: Sender<Data>,
     ) -> QueryId {
-        let query_id = QueryId(self.next_query_id);
-        self.next_query_id += 1;
+        let query_id = self.query_id_generator.next_id();
+        if query.limit > self.max_blocks_per_query {
+            debug!(
+                "Truncating query {query_id} from {} to {} blocks.",
+                query.limit, self.max_blocks_per_query
+            );
+        }
+        let query = InternalQuery { limit: query.limit.min(self.max_blocks_per_query), ..query };
         let storage_reader_clone = self.storage_reader.clone();
-        self.query_execution_set.push(tokio::task::spawn(async move {
+        let query_execution_permits = self.query_execution_permits.clone();
+        let max_retries = self.max_retries;
+        let retry_base_delay = self.retry_base_delay;
+        let data_transformer = self.data_transformer.clone();
+        let send_response_summary = self.send_response_summary;
+        let query_execution = async move {
             {
-                let txn = storage_reader_clone.begin_ro_txn().map_err(|err| {
-                    DBExecutorError::DBInternalError { query_id, storage_error: err }
-                })?;
+                // The permit is released when it's dropped at the end of the query execution.
+                let _permit = query_execution_permits
+                    .acquire_owned()
+                    .await
+                    .expect("The query execution semaphore should never be closed.");
+                // All the blocks of the query are read from this transaction, so that the query
+                // sees a consistent snapshot of the storage.
+                let txn = utils::retry_on_transient_error(max_retries, retry_base_delay, || {
+                    storage_reader_clone.begin_ro_txn().map_err(|err| {
+                        DBExecutorError::DBInternalError { query_id, storage_error: err }
+                    })
+                })
+                .await?;
                 let start_block_number = match query.start_block {
                     BlockHashOrNumber::Number(BlockNumber(num)) => num,
                     BlockHashOrNumber::Hash(block_hash) => {
-                        txn.get_block_number_by_hash(&block_hash)
-                            .map_err(|err| DBExecutorError::DBInternalError {
-                                query_id,
-                                storage_error: err,
-                            })?
-                            .ok_or(DBExecutorError::BlockNotFound {
-                                block_hash_or_number: BlockHashOrNumber::Hash(block_hash),
-                                query_id,
-                            })?
-                            .0
+                        utils::block_data_from_storage_result(
+                            txn.get_block_number_by_hash(&block_hash),
+                            BlockHashOrNumber::Hash(block_hash),
+                            query_id,
+                        )?
+                        .0
                     }
                 };
-                for block_counter in 0..query.limit {
-                    let block_number = BlockNumber(utils::calculate_block_number(
-                        query,
-                        start_block_number,
-                        block_counter,
-                        query_id,
-                    )?);
+                let mut summary = ResponseSummary::default();
+                for block_number in utils::block_numbers_iter(query, start_block_number) {
+                    // No need to read blocks that no one will receive.
+                    if sender.is_closed() {
+                        return Err(DBExecutorError::ChannelClosed { query_id });
+                    }
                     let data = data_type.fetch_block_data_from_db(block_number, query_id, &txn)?;
-                    // Using poll_fn because Sender::poll_ready is not a future
-                    match poll_fn(|cx| sender.poll_ready(cx)).await {
-                        Ok(()) => {
-                            if let Err(e) = sender.start_send(data) {
-                                // TODO: consider implement retry mechanism.
-                                return Err(DBExecutorError::SendError { query_id, send_error: e });
-                            };
-                        }
-                        Err(e) => {
-                            return Err(DBExecutorError::SendError { query_id, send_error: e });
+                    let data = match data_transformer.as_ref() {
+                        Some(data_transformer) => {
+                            data_transformer.transform(data, block_number, query_id)?
                         }
-                    }
+                        None => data,
+                    };
+                    utils::send_data(&mut sender, data, query_id).await?;
+                    summary.num_blocks += 1;
+                    summary.highest_block = summary.highest_block.max(Some(block_number));
+                }
+                if send_response_summary {
+                    utils::send_data(&mut sender, Data::FinWithSummary { summary }, query_id)
+                        .await?;
                 }
                 Ok(query_id)
             }
-        }));
+        };
+        let query_execution = query_execution.instrument(info_span!(
+            "db_executor_query",
+            query_id = %query_id,
+            start_block = ?query.start_block,
+            direction = ?query.direction,
+            limit = query.limit,
+            step = query.step,
+        ));
+        if self.is_paused {
+            self.paused_query_executions.push(query_execution.boxed());
+        } else {
+            self.query_execution_set.push(tokio::task::spawn(query_execution));
+        }
+        query_id
+    }
+
+    fn register_chain_tip_query(&mut self, mut sender: Sender<Data>) -> QueryId {
+        let query_id = self.query_id_generator.next_id();
+        let storage_reader_clone = self.storage_reader.clone();
+        let query_execution_permits = self.query_execution_permits.clone();
+        let query_execution = async move {
+            let _permit = query_execution_permits
+                .acquire_owned()
+                .await
+                .expect("The query execution semaphore should never be closed.");
+            let txn = storage_reader_clone
+                .begin_ro_txn()
+                .map_err(|err| DBExecutorError::DBInternalError { query_id, storage_error: err })?;
+            let header_marker = txn
+                .get_header_marker()
+                .map_err(|err| DBExecutorError::DBInternalError { query_id, storage_error: err })?;
+            let Some(block_number) = header_marker.prev() else {
+                return Ok(query_id);
+            };
+            let header = utils::block_data_from_storage_result(
+                txn.get_block_header(block_number),
+                BlockHashOrNumber::Number(block_number),
+                query_id,
+            )?;
+            utils::send_data(
+                &mut sender,
+                Data::ChainTip { block_number, block_hash: header.block_hash },
+                query_id,
+            )
+            .await?;
+            Ok(query_id)
+        };
+        let query_execution = query_execution
+            .instrument(info_span!("db_executor_chain_tip_query", query_id = %query_id));
+        if self.is_paused {
+            self.paused_query_executions.push(query_execution.boxed());
+        } else {
+            self.query_execution_set.push(tokio::task::spawn(query_execution));
+        }
         query_id
     }
+
+    fn pause(&mut self) {
+        self.is_paused = true;
+    }
+
+    fn resume(&mut self) {
+        self.is_paused = false;
+        for query_execution in self.paused_query_executions.drain(..) {
+            self.query_execution_set.push(tokio::task::spawn(query_execution));
+        }
+    }
+
+    fn set_data_transformer(&mut self, data_transformer: Arc<dyn DataTransformer>) {
+        self.data_transformer = Some(data_transformer);
+    }
 }
 
-impl Stream for BlockHeaderDBExecutor {
+// The queries run in spawned tasks, which would otherwise keep reading from the storage after the
+// executor (e.g. with the network manager that owns it) is dropped.
+impl<Reader: StorageTxnOpener> Drop for BlockHeaderDBExecutor<Reader> {
+    fn drop(&mut self) {
+        for query_execution in self.query_execution_set.iter() {
+            query_execution.abort();
+        }
+    }
+}
+
+impl<Reader: StorageTxnOpener> Stream for BlockHeaderDBExecutor<Reader> {
     type Item = Result<QueryId, DBExecutorError>;
 
     fn poll_next(
@@ -207,6 +444,18 @@ pub(crate) fn poll_query_execution_set(
     }
 }
 
+/// Opens the read-only storage transactions that the queries of a [`BlockHeaderDBExecutor`] read
+/// from.
+pub trait StorageTxnOpener: Clone + Send + Sync + Unpin + 'static {
+    fn begin_ro_txn(&self) -> StorageResult<StorageTxn<'_, db::RO>>;
+}
+
+impl StorageTxnOpener for StorageReader {
+    fn begin_ro_txn(&self) -> StorageResult<StorageTxn<'_, db::RO>> {
+        StorageReader::begin_ro_txn(self)
+    }
+}
+
 #[cfg_attr(test, automock)]
 // we need to tell clippy to ignore the "needless" lifetime warning because it's not true.
 // we do need the lifetime for the automock, following clippy's suggestion will break the code.
@@ -220,6 +469,17 @@ pub trait FetchBlockDataFromDb {
     ) -> Result<Data, DBExecutorError>;
 }
 
+/// Transforms the data of each block that a query read from the storage before it's sent, e.g. to
+/// attach additional metadata to it.
+pub trait DataTransformer: Send + Sync {
+    fn transform(
+        &self,
+        data: Data,
+        block_number: BlockNumber,
+        query_id: QueryId,
+    ) -> Result<Data, DBExecutorError>;
+}
+
 impl FetchBlockDataFromDb for DataType {
     fn fetch_block_data_from_db(
         &self,
@@ -229,16 +489,11 @@ impl FetchBlockDataFromDb for DataType {
     ) -> Result<Data, DBExecutorError> {
         match self {
             DataType::SignedBlockHeader => {
-                let header = txn
-                    .get_block_header(block_number)
-                    .map_err(|err| DBExecutorError::DBInternalError {
-                        query_id,
-                        storage_error: err,
-                    })?
-                    .ok_or(DBExecutorError::BlockNotFound {
-                        block_hash_or_number: BlockHashOrNumber::Number(block_number),
-                        query_id,
-                    })?;
+                let header = utils::block_data_from_storage_result(
+                    txn.get_block_header(block_number),
+                    BlockHashOrNumber::Number(block_number),
+                    query_id,
+                )?;
                 let signature = txn
                     .get_block_signature(block_number)
                     .map_err(|err| DBExecutorError::DBInternalError {
@@ -249,16 +504,11 @@ impl FetchBlockDataFromDb for DataType {
                 Ok(Data::BlockHeaderAndSignature { header, signatures: vec![signature] })
             }
             DataType::StateDiff => {
-                let state_diff = txn
-                    .get_state_diff(block_number)
-                    .map_err(|err| DBExecutorError::DBInternalError {
-                        query_id,
-                        storage_error: err,
-                    })?
-                    .ok_or(DBExecutorError::BlockNotFound {
-                        block_hash_or_number: BlockHashOrNumber::Number(block_number),
-                        query_id,
-                    })?;
+                let state_diff = utils::block_data_from_storage_result(
+                    txn.get_state_diff(block_number),
+                    BlockHashOrNumber::Number(block_number),
+                    query_id,
+                )?;
                 Ok(Data::StateDiff { state_diff })
             }
         }
@@ -1,4 +1,9 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::Poll;
+use std::time::Duration;
 
 use assert_matches::assert_matches;
 use futures::channel::mpsc::Receiver;
@@ -6,23 +11,42 @@ use futures::future::poll_fn;
 use futures::stream::SelectAll;
 use futures::{FutureExt, StreamExt};
 use indexmap::IndexMap;
+use papyrus_storage::db::{self, DbError};
 use papyrus_storage::header::{HeaderStorageReader, HeaderStorageWriter};
 use papyrus_storage::state::StateStorageWriter;
 use papyrus_storage::test_utils::get_test_storage;
-use papyrus_storage::StorageWriter;
+use papyrus_storage::{StorageError, StorageReader, StorageResult, StorageTxn, StorageWriter};
 use rand::random;
 use starknet_api::block::{BlockHash, BlockHeader, BlockNumber, BlockSignature};
+use starknet_api::crypto::Signature;
 use starknet_api::state::{StateDiff, ThinStateDiff};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
 
 use super::Data::BlockHeaderAndSignature;
-use crate::db_executor::{DBExecutor, DBExecutorError, Data, MockFetchBlockDataFromDb, QueryId};
+use crate::db_executor::{
+    utils,
+    DBExecutor,
+    DBExecutorConfig,
+    DBExecutorError,
+    Data,
+    DataTransformer,
+    MockFetchBlockDataFromDb,
+    QueryId,
+    ResponseSummary,
+    StorageTxnOpener,
+};
 use crate::{BlockHashOrNumber, DataType, Direction, InternalQuery};
 const BUFFER_SIZE: usize = 10;
 
 #[tokio::test]
 async fn header_db_executor_can_register_and_run_a_query() {
     let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
-    let mut db_executor = super::BlockHeaderDBExecutor::new(storage_reader);
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
 
     // put some data in the storage.
     const NUM_OF_BLOCKS: u64 = 10;
@@ -39,7 +63,7 @@ async fn header_db_executor_can_register_and_run_a_query() {
         enum_iterator::all::<DataType>()
             .map(|data_type| {
                 let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
-                let query_id = db_executor.register_query(query, data_type, sender);
+                let query_id = db_executor.register_query(query, Box::new(data_type), sender);
                 (query_id, (receiver, data_type))
             })
             .unzip();
@@ -108,7 +132,8 @@ async fn header_db_executor_start_block_given_by_hash() {
         .unwrap()
         .block_hash;
 
-    let mut db_executor = super::BlockHeaderDBExecutor::new(storage_reader);
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
 
     // register a query.
     let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
@@ -118,7 +143,7 @@ async fn header_db_executor_start_block_given_by_hash() {
         limit: NUM_OF_BLOCKS,
         step: 1,
     };
-    let query_id = db_executor.register_query(query, DataType::SignedBlockHeader, sender);
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
 
     // run the executor and collect query results.
     tokio::select! {
@@ -138,7 +163,8 @@ async fn header_db_executor_start_block_given_by_hash() {
 #[tokio::test]
 async fn header_db_executor_query_of_missing_block() {
     let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
-    let mut db_executor = super::BlockHeaderDBExecutor::new(storage_reader);
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
 
     const NUM_OF_BLOCKS: u64 = 15;
     insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
@@ -165,7 +191,7 @@ async fn header_db_executor_query_of_missing_block() {
             }
         },
     );
-    let _query_id = db_executor.register_query(query, mock_data_type, sender);
+    let _query_id = db_executor.register_query(query, Box::new(mock_data_type), sender);
 
     tokio::select! {
         res = db_executor.next() => {
@@ -178,10 +204,306 @@ async fn header_db_executor_query_of_missing_block() {
     }
 }
 
+#[tokio::test]
+async fn header_db_executor_backward_query_stops_at_genesis() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    const NUM_OF_BLOCKS: u64 = 10;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+
+    const START_BLOCK_NUMBER: u64 = 2;
+    // register a query whose range crosses below the genesis block.
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(START_BLOCK_NUMBER)),
+        direction: Direction::Backward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+
+    let block_numbers = receiver
+        .map(|data| {
+            let BlockHeaderAndSignature { header, .. } = data else {
+                panic!("Unexpected data type");
+            };
+            header.block_number.0
+        })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(block_numbers, (0..=START_BLOCK_NUMBER).rev().collect::<Vec<_>>());
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+}
+
+#[tokio::test]
+async fn header_db_executor_reads_query_from_a_single_snapshot() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    const NUM_OF_BLOCKS: u64 = 3;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+
+    // The channel has room for a single message, so the query waits for us to consume each block.
+    let (sender, mut receiver) = futures::channel::mpsc::channel(0);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 2 * NUM_OF_BLOCKS,
+        step: 1,
+    };
+    db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+
+    // The query took its snapshot before sending the first block, so the blocks written from now
+    // on shouldn't reach it.
+    let first_data = receiver.next().await.unwrap();
+    insert_to_storage_test_blocks(NUM_OF_BLOCKS..2 * NUM_OF_BLOCKS, &mut storage_writer);
+
+    let block_numbers = futures::stream::once(async { first_data })
+        .chain(receiver)
+        .map(|data| {
+            let BlockHeaderAndSignature { header, .. } = data else {
+                panic!("Unexpected data type");
+            };
+            header.block_number.0
+        })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(block_numbers, (0..NUM_OF_BLOCKS).collect::<Vec<_>>());
+    assert_matches!(
+        db_executor.next().await.unwrap(),
+        Err(DBExecutorError::BlockNotFound {
+            block_hash_or_number: BlockHashOrNumber::Number(BlockNumber(NUM_OF_BLOCKS)),
+            ..
+        })
+    );
+}
+
+#[tokio::test]
+async fn header_db_executor_chain_tip_query_returns_the_head() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader.clone(), DBExecutorConfig::default());
+
+    const NUM_OF_BLOCKS: u64 = 5;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+    let head_block_number = BlockNumber(NUM_OF_BLOCKS - 1);
+    let head_block_hash = storage_reader
+        .begin_ro_txn()
+        .unwrap()
+        .get_block_header(head_block_number)
+        .unwrap()
+        .unwrap()
+        .block_hash;
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query_id = db_executor.register_chain_tip_query(sender);
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+
+    assert_eq!(
+        receiver.collect::<Vec<_>>().await,
+        vec![Data::ChainTip { block_number: head_block_number, block_hash: head_block_hash }]
+    );
+}
+
+#[tokio::test]
+async fn header_db_executor_chain_tip_query_of_empty_storage() {
+    let ((storage_reader, _storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query_id = db_executor.register_chain_tip_query(sender);
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+    assert!(receiver.collect::<Vec<_>>().await.is_empty());
+}
+
+#[tokio::test]
+async fn header_db_executor_aborts_running_queries_when_dropped() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    const NUM_OF_BLOCKS: u64 = 10;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+
+    // The channel has room for a single message, so the query waits for us to consume each block.
+    let (sender, mut receiver) = futures::channel::mpsc::channel(0);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+    receiver.next().await.unwrap();
+
+    drop(db_executor);
+
+    // The query was aborted, so its sender is dropped before all the blocks are sent.
+    let num_of_remaining_blocks = tokio::time::timeout(Duration::from_secs(5), receiver.count())
+        .await
+        .expect("The query wasn't aborted");
+    assert!(num_of_remaining_blocks < (NUM_OF_BLOCKS - 1) as usize);
+}
+
+#[tokio::test]
+async fn header_db_executor_truncates_query_to_max_blocks_per_query() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    const MAX_BLOCKS_PER_QUERY: u64 = 3;
+    let mut db_executor = super::BlockHeaderDBExecutor::new(
+        storage_reader,
+        DBExecutorConfig { max_blocks_per_query: MAX_BLOCKS_PER_QUERY, ..Default::default() },
+    );
+
+    const NUM_OF_BLOCKS: u64 = 10;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+
+    let block_numbers = receiver
+        .map(|data| {
+            let BlockHeaderAndSignature { header, .. } = data else {
+                panic!("Unexpected data type");
+            };
+            header.block_number.0
+        })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(block_numbers, (0..MAX_BLOCKS_PER_QUERY).collect::<Vec<_>>());
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+}
+
+#[tokio::test]
+async fn header_db_executor_ends_query_with_summary() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor = super::BlockHeaderDBExecutor::new(
+        storage_reader,
+        DBExecutorConfig { send_response_summary: true, ..Default::default() },
+    );
+
+    const NUM_OF_BLOCKS: u64 = 10;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(NUM_OF_BLOCKS - 1)),
+        direction: Direction::Backward,
+        limit: 4,
+        step: 2,
+    };
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+
+    let mut data = receiver.collect::<Vec<_>>().await;
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+    assert_eq!(
+        data.pop().unwrap(),
+        Data::FinWithSummary {
+            summary: ResponseSummary {
+                num_blocks: 4,
+                highest_block: Some(BlockNumber(NUM_OF_BLOCKS - 1)),
+                total_bytes: 0,
+            }
+        }
+    );
+    assert_eq!(data.len(), 4);
+    assert!(data.iter().all(|data| matches!(data, BlockHeaderAndSignature { .. })));
+}
+
+#[tokio::test]
+async fn header_db_executor_transforms_data_before_sending() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    // Tags each header by adding a signature made of its block number.
+    struct TagTransformer;
+    impl DataTransformer for TagTransformer {
+        fn transform(
+            &self,
+            data: Data,
+            block_number: BlockNumber,
+            _query_id: QueryId,
+        ) -> Result<Data, DBExecutorError> {
+            let BlockHeaderAndSignature { header, mut signatures } = data else {
+                panic!("Unexpected data type");
+            };
+            signatures.push(tag(block_number));
+            Ok(BlockHeaderAndSignature { header, signatures })
+        }
+    }
+    fn tag(block_number: BlockNumber) -> BlockSignature {
+        BlockSignature(Signature { r: block_number.0.into(), s: block_number.0.into() })
+    }
+    db_executor.set_data_transformer(Arc::new(TagTransformer));
+
+    const NUM_OF_BLOCKS: u64 = 5;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+
+    let data = receiver.collect::<Vec<_>>().await;
+    assert_eq!(data.len(), NUM_OF_BLOCKS as usize);
+    for data in data {
+        let BlockHeaderAndSignature { header, signatures } = data else {
+            panic!("Unexpected data type");
+        };
+        assert_eq!(signatures, vec![BlockSignature::default(), tag(header.block_number)]);
+    }
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+}
+
+#[tokio::test]
+async fn header_db_executor_error_carries_peeked_query_id() {
+    let ((storage_reader, _), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    // Register a query before the one we check so that its id isn't the first one.
+    let (sender, _receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 0,
+        step: 1,
+    };
+    let first_query_id =
+        db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), first_query_id);
+
+    // The storage is empty, so this query fails.
+    let peeked_query_id = db_executor.peek_next_query_id();
+    let (sender, _receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery { limit: 1, ..query };
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+    assert_eq!(query_id, peeked_query_id);
+
+    let err = db_executor.next().await.unwrap().unwrap_err();
+    assert_matches!(err, DBExecutorError::BlockNotFound { .. });
+    assert_eq!(err.query_id(), Some(peeked_query_id));
+}
+
 #[test]
 fn header_db_executor_stream_pending_with_no_query() {
     let ((storage_reader, _), _temp_dir) = get_test_storage();
-    let mut db_executor = super::BlockHeaderDBExecutor::new(storage_reader);
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
 
     // poll without registering a query.
     assert!(poll_fn(|cx| db_executor.poll_next_unpin(cx)).now_or_never().is_none());
@@ -190,7 +512,8 @@ fn header_db_executor_stream_pending_with_no_query() {
 #[tokio::test]
 async fn header_db_executor_can_receive_queries_after_stream_is_exhausted() {
     let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
-    let mut db_executor = super::BlockHeaderDBExecutor::new(storage_reader);
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
 
     const NUM_OF_BLOCKS: u64 = 10;
     insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
@@ -209,7 +532,7 @@ async fn header_db_executor_can_receive_queries_after_stream_is_exhausted() {
             .expect_fetch_block_data_from_db()
             .times(NUM_OF_BLOCKS as usize)
             .returning(|_, _, _| Ok(Data::default()));
-        let query_id = db_executor.register_query(query, mock_data_type, sender);
+        let query_id = db_executor.register_query(query, Box::new(mock_data_type), sender);
 
         // run the executor and collect query results.
         receiver.collect::<Vec<_>>().await;
@@ -229,7 +552,8 @@ async fn header_db_executor_can_receive_queries_after_stream_is_exhausted() {
 #[tokio::test]
 async fn header_db_executor_drop_receiver_before_query_is_done() {
     let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
-    let mut db_executor = super::BlockHeaderDBExecutor::new(storage_reader);
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
 
     const NUM_OF_BLOCKS: u64 = 10;
     insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
@@ -244,15 +568,461 @@ async fn header_db_executor_drop_receiver_before_query_is_done() {
     drop(receiver);
 
     // register a query.
-    let _query_id = db_executor.register_query(query, MockFetchBlockDataFromDb::new(), sender);
+    let _query_id =
+        db_executor.register_query(query, Box::new(MockFetchBlockDataFromDb::new()), sender);
 
     // executor should return an error.
     let res = db_executor.next().await;
     assert!(res.unwrap().is_err());
 }
 
+#[tokio::test]
+async fn header_db_executor_stops_reading_once_receiver_is_dropped() {
+    let ((storage_reader, _), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    const NUM_OF_BLOCKS: u64 = 100;
+    // A zero sized buffer makes the query wait on the receiver after reading a block.
+    let (sender, mut receiver) = futures::channel::mpsc::channel(0);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    let num_of_reads = Arc::new(Mutex::new(0));
+    let mut mock_data_type = MockFetchBlockDataFromDb::new();
+    let num_of_reads_clone = num_of_reads.clone();
+    mock_data_type.expect_fetch_block_data_from_db().returning(move |_, _, _| {
+        *num_of_reads_clone.lock().unwrap() += 1;
+        Ok(Data::default())
+    });
+    let query_id = db_executor.register_query(query, Box::new(mock_data_type), sender);
+
+    receiver.next().await.unwrap();
+    drop(receiver);
+
+    let err = db_executor.next().await.unwrap().unwrap_err();
+    assert_matches!(
+        err,
+        DBExecutorError::ChannelClosed { query_id: err_query_id } if err_query_id == query_id
+    );
+    // The query may read the blocks that fit in the channel before noticing that the receiver was
+    // dropped, but it shouldn't read any further.
+    assert!(*num_of_reads.lock().unwrap() <= 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn header_db_executor_limits_concurrent_queries() {
+    let ((storage_reader, _), _temp_dir) = get_test_storage();
+    const CONCURRENCY: usize = 2;
+    const NUM_OF_QUERIES: usize = 5;
+    const NUM_OF_BLOCKS: u64 = 5;
+    let mut db_executor = super::BlockHeaderDBExecutor::new(
+        storage_reader,
+        DBExecutorConfig { concurrency: CONCURRENCY, ..Default::default() },
+    );
+
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    // Records the queries that started reading from the storage.
+    let started_query_ids = Arc::new(Mutex::new(HashSet::new()));
+    let receivers = (0..NUM_OF_QUERIES)
+        .map(|_| {
+            // A zero sized buffer makes the query wait on the receiver after reading a block.
+            let (sender, receiver) = futures::channel::mpsc::channel(0);
+            let mut mock_data_type = MockFetchBlockDataFromDb::new();
+            let started_query_ids = started_query_ids.clone();
+            mock_data_type.expect_fetch_block_data_from_db().returning(move |_, query_id, _| {
+                started_query_ids.lock().unwrap().insert(query_id);
+                Ok(Data::default())
+            });
+            db_executor.register_query(query, Box::new(mock_data_type), sender);
+            receiver
+        })
+        .collect::<Vec<_>>();
+
+    // Let the queries run until they're all blocked on their receivers or on a permit. Since the
+    // time is paused, the sleep ends only once all the tasks are idle.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(started_query_ids.lock().unwrap().len(), CONCURRENCY);
+
+    // Once the receivers are consumed, all the queries should finish.
+    for receiver in receivers {
+        assert_eq!(receiver.collect::<Vec<_>>().await.len(), NUM_OF_BLOCKS as usize);
+    }
+    for _ in 0..NUM_OF_QUERIES {
+        db_executor.next().await.unwrap().unwrap();
+    }
+    assert_eq!(started_query_ids.lock().unwrap().len(), NUM_OF_QUERIES);
+}
+
+#[tokio::test]
+async fn header_db_executor_with_zero_concurrency_runs_queries() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    let mut db_executor = super::BlockHeaderDBExecutor::new(
+        storage_reader,
+        DBExecutorConfig { concurrency: 0, ..Default::default() },
+    );
+
+    const NUM_OF_BLOCKS: u64 = 5;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+
+    assert_eq!(receiver.collect::<Vec<_>>().await.len(), NUM_OF_BLOCKS as usize);
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+}
+
+#[tokio::test]
+async fn header_db_executor_starts_queries_registered_while_paused_on_resume() {
+    let ((storage_reader, _), _temp_dir) = get_test_storage();
+    const NUM_OF_BLOCKS: u64 = 5;
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    // Records whether the query started reading from the storage.
+    let started = Arc::new(Mutex::new(false));
+    let mut mock_data_type = MockFetchBlockDataFromDb::new();
+    let started_clone = started.clone();
+    mock_data_type.expect_fetch_block_data_from_db().returning(move |_, _, _| {
+        *started_clone.lock().unwrap() = true;
+        Ok(Data::default())
+    });
+
+    db_executor.pause();
+    let query_id = db_executor.register_query(query, Box::new(mock_data_type), sender);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!*started.lock().unwrap());
+    assert!(poll_fn(|cx| db_executor.poll_next_unpin(cx)).now_or_never().is_none());
+
+    db_executor.resume();
+    assert_eq!(receiver.collect::<Vec<_>>().await.len(), NUM_OF_BLOCKS as usize);
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+    assert!(*started.lock().unwrap());
+}
+
+fn inner_db_error(query_id: QueryId, error: libmdbx::Error) -> DBExecutorError {
+    DBExecutorError::DBInternalError {
+        query_id,
+        storage_error: StorageError::InnerError(DbError::Inner(error)),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn opening_storage_txn_retries_reader_slot_errors() {
+    let ((storage_reader, _), _temp_dir) = get_test_storage();
+    const NUM_OF_FAILURES: u32 = 2;
+    let query_id = QueryId(0);
+
+    // Fail opening the transaction as if all the reader slots were taken and then succeed.
+    let mut num_of_attempts = 0;
+    let txn_result =
+        utils::retry_on_transient_error(NUM_OF_FAILURES, Duration::from_millis(10), || {
+            num_of_attempts += 1;
+            if num_of_attempts <= NUM_OF_FAILURES {
+                return Err(inner_db_error(query_id, libmdbx::Error::ReadersFull));
+            }
+            storage_reader.begin_ro_txn().map_err(|storage_error| {
+                DBExecutorError::DBInternalError { query_id, storage_error }
+            })
+        })
+        .await;
+    assert!(txn_result.is_ok());
+    assert_eq!(num_of_attempts, NUM_OF_FAILURES + 1);
+
+    // Give up once the retries are exhausted.
+    let mut num_of_attempts = 0;
+    let txn_result =
+        utils::retry_on_transient_error(NUM_OF_FAILURES, Duration::from_millis(10), || {
+            num_of_attempts += 1;
+            Err::<(), _>(inner_db_error(query_id, libmdbx::Error::Busy))
+        })
+        .await;
+    assert!(txn_result.unwrap_err().is_transient());
+    assert_eq!(num_of_attempts, NUM_OF_FAILURES + 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn opening_storage_txn_does_not_retry_other_db_errors() {
+    let mut num_of_attempts = 0;
+    let txn_result = utils::retry_on_transient_error(3, Duration::from_millis(10), || {
+        num_of_attempts += 1;
+        Err::<(), _>(inner_db_error(QueryId(0), libmdbx::Error::Corrupted))
+    })
+    .await;
+    assert_matches!(
+        txn_result,
+        Err(DBExecutorError::DBInternalError {
+            storage_error: StorageError::InnerError(DbError::Inner(libmdbx::Error::Corrupted)),
+            ..
+        })
+    );
+    assert_eq!(num_of_attempts, 1);
+}
+
+// A storage reader whose first transactions fail as if all the database reader slots were taken.
+#[derive(Clone)]
+struct ReaderSlotsTakenStorageReader {
+    storage_reader: StorageReader,
+    remaining_failures: Arc<AtomicU32>,
+}
+
+impl StorageTxnOpener for ReaderSlotsTakenStorageReader {
+    fn begin_ro_txn(&self) -> StorageResult<StorageTxn<'_, db::RO>> {
+        if self
+            .remaining_failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| failures.checked_sub(1))
+            .is_ok()
+        {
+            return Err(StorageError::InnerError(DbError::Inner(libmdbx::Error::ReadersFull)));
+        }
+        self.storage_reader.begin_ro_txn()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn header_db_executor_runs_query_after_transient_storage_errors() {
+    let ((storage_reader, mut storage_writer), _temp_dir) = get_test_storage();
+    const NUM_OF_BLOCKS: u64 = 5;
+    insert_to_storage_test_blocks_up_to(NUM_OF_BLOCKS, &mut storage_writer);
+    let remaining_failures = Arc::new(AtomicU32::new(2));
+    let mut db_executor = super::BlockHeaderDBExecutor::new(
+        ReaderSlotsTakenStorageReader {
+            storage_reader,
+            remaining_failures: remaining_failures.clone(),
+        },
+        DBExecutorConfig { send_response_summary: true, ..Default::default() },
+    );
+
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: NUM_OF_BLOCKS,
+        step: 1,
+    };
+    let query_id = db_executor.register_query(query, Box::new(DataType::SignedBlockHeader), sender);
+    assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+    assert_eq!(remaining_failures.load(Ordering::SeqCst), 0);
+
+    let data = receiver.collect::<Vec<_>>().await;
+    let (fin, headers) = data.split_last().unwrap();
+    let block_numbers = headers
+        .iter()
+        .map(|data| match data {
+            BlockHeaderAndSignature { header, .. } => header.block_number.0,
+            _ => panic!("Expected a block header, got {data:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(block_numbers, (0..NUM_OF_BLOCKS).collect::<Vec<_>>());
+    assert_eq!(
+        fin,
+        &Data::FinWithSummary {
+            summary: ResponseSummary {
+                num_blocks: NUM_OF_BLOCKS,
+                highest_block: Some(BlockNumber(NUM_OF_BLOCKS - 1)),
+                total_bytes: 0,
+            },
+        }
+    );
+}
+
+// Records the query ids of the db executor query spans that were entered.
+#[derive(Clone, Default)]
+struct EnteredQuerySpansRecorder {
+    query_ids: Arc<Mutex<Vec<String>>>,
+}
+
+struct QueryIdField(String);
+
+impl Visit for QueryIdField {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "query_id" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for EnteredQuerySpansRecorder {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: layer::Context<'_, S>) {
+        if attrs.metadata().name() != "db_executor_query" {
+            return;
+        }
+        let mut query_id_field = QueryIdField(String::new());
+        attrs.record(&mut query_id_field);
+        ctx.span(id).unwrap().extensions_mut().insert(query_id_field);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: layer::Context<'_, S>) {
+        if let Some(QueryIdField(query_id)) = ctx.span(id).unwrap().extensions().get() {
+            self.query_ids.lock().unwrap().push(query_id.clone());
+        }
+    }
+}
+
+#[tokio::test]
+async fn header_db_executor_runs_query_in_span() {
+    let recorder = EnteredQuerySpansRecorder::default();
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+
+    let ((storage_reader, _), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    // Run two queries to check that each one runs in a span with its own id.
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 0,
+        step: 1,
+    };
+    for _ in 0..2 {
+        let (sender, _receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+        let query_id =
+            db_executor.register_query(query, Box::new(MockFetchBlockDataFromDb::new()), sender);
+        assert_eq!(db_executor.next().await.unwrap().unwrap(), query_id);
+        assert_eq!(recorder.query_ids.lock().unwrap().last(), Some(&query_id.to_string()));
+    }
+}
+
+#[tokio::test]
+async fn header_db_executor_reports_storage_errors() {
+    let ((storage_reader, _), _temp_dir) = get_test_storage();
+    let mut db_executor =
+        super::BlockHeaderDBExecutor::new(storage_reader, DBExecutorConfig::default());
+
+    const FAILED_BLOCK: u64 = 2;
+    let (sender, receiver) = futures::channel::mpsc::channel(BUFFER_SIZE);
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 5,
+        step: 1,
+    };
+    // Fail reading one of the blocks as if the storage failed.
+    let mut mock_data_type = MockFetchBlockDataFromDb::new();
+    mock_data_type.expect_fetch_block_data_from_db().times((FAILED_BLOCK + 1) as usize).returning(
+        |block_number, query_id, _| {
+            let storage_result = if block_number.0 == FAILED_BLOCK {
+                Err(StorageError::DBInconsistency { msg: "injected error".to_string() })
+            } else {
+                Ok(Some(Data::default()))
+            };
+            utils::block_data_from_storage_result(
+                storage_result,
+                BlockHashOrNumber::Number(block_number),
+                query_id,
+            )
+        },
+    );
+    let query_id = db_executor.register_query(query, Box::new(mock_data_type), sender);
+
+    assert_eq!(receiver.collect::<Vec<_>>().await.len(), FAILED_BLOCK as usize);
+    let err = db_executor.next().await.unwrap().unwrap_err();
+    assert_matches!(
+        err,
+        DBExecutorError::DBInternalError {
+            query_id: res_query_id,
+            storage_error: StorageError::DBInconsistency { .. },
+        } if res_query_id == query_id
+    );
+}
+
+#[test]
+fn storage_error_is_not_reported_as_block_not_found() {
+    let query_id = QueryId(0);
+    let block_hash_or_number = BlockHashOrNumber::Number(BlockNumber(0));
+
+    let res = utils::block_data_from_storage_result::<BlockHeader>(
+        Err(StorageError::DBInconsistency { msg: "injected error".to_string() }),
+        block_hash_or_number,
+        query_id,
+    );
+    assert_matches!(
+        res,
+        Err(DBExecutorError::DBInternalError {
+            query_id: res_query_id,
+            storage_error: StorageError::DBInconsistency { .. },
+        }) if res_query_id == query_id
+    );
+
+    let res = utils::block_data_from_storage_result::<BlockHeader>(
+        Ok(None),
+        block_hash_or_number,
+        query_id,
+    );
+    assert_matches!(
+        res,
+        Err(DBExecutorError::BlockNotFound {
+            block_hash_or_number: res_block_hash_or_number,
+            query_id: res_query_id,
+        }) if res_block_hash_or_number == block_hash_or_number && res_query_id == query_id
+    );
+}
+
+fn block_numbers_of(direction: Direction, start_block: u64, limit: u64, step: u64) -> Vec<u64> {
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(start_block)),
+        direction,
+        limit,
+        step,
+    };
+    utils::block_numbers_iter(query, start_block).map(|block_number| block_number.0).collect()
+}
+
+#[test]
+fn block_numbers_iter_forward() {
+    assert_eq!(block_numbers_of(Direction::Forward, 5, 3, 1), vec![5, 6, 7]);
+}
+
+#[test]
+fn block_numbers_iter_backward() {
+    assert_eq!(block_numbers_of(Direction::Backward, 5, 3, 1), vec![5, 4, 3]);
+}
+
+#[test]
+fn block_numbers_iter_with_step() {
+    assert_eq!(block_numbers_of(Direction::Forward, 5, 3, 4), vec![5, 9, 13]);
+    assert_eq!(block_numbers_of(Direction::Backward, 13, 3, 4), vec![13, 9, 5]);
+}
+
+#[test]
+fn block_numbers_iter_clamped() {
+    assert_eq!(block_numbers_of(Direction::Backward, 5, 10, 2), vec![5, 3, 1]);
+    assert_eq!(
+        block_numbers_of(Direction::Forward, u64::MAX - 2, 10, 2),
+        vec![u64::MAX - 2, u64::MAX]
+    );
+    assert_eq!(block_numbers_of(Direction::Forward, 5, 0, 1), Vec::<u64>::new());
+}
+
 fn insert_to_storage_test_blocks_up_to(num_of_blocks: u64, storage_writer: &mut StorageWriter) {
-    for i in 0..num_of_blocks {
+    insert_to_storage_test_blocks(0..num_of_blocks, storage_writer);
+}
+
+fn insert_to_storage_test_blocks(block_numbers: Range<u64>, storage_writer: &mut StorageWriter) {
+    for i in block_numbers {
         let block_header = BlockHeader {
             block_number: BlockNumber(i),
             block_hash: BlockHash(random::<u64>().into()),
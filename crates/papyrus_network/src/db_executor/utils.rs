@@ -1,24 +1,83 @@
-use super::{DBExecutorError, QueryId};
-use crate::{Direction, InternalQuery};
+use std::time::Duration;
 
-pub(crate) fn calculate_block_number(
+use futures::channel::mpsc::Sender;
+use futures::future::poll_fn;
+use papyrus_storage::StorageResult;
+use starknet_api::block::BlockNumber;
+use tracing::debug;
+
+use super::{DBExecutorError, Data, QueryId};
+use crate::{BlockHashOrNumber, Direction, InternalQuery};
+
+/// Returns the numbers of the blocks that the query asks for, in the order they should be sent,
+/// given the number of the query's start block. The iteration stops early once it passes the
+/// genesis block (for backward queries) or the maximal block number (for forward queries).
+pub(crate) fn block_numbers_iter(
     query: InternalQuery,
     start_block: u64,
-    read_blocks_counter: u64,
+) -> impl Iterator<Item = BlockNumber> {
+    (0..query.limit).map_while(move |read_blocks_counter| {
+        let blocks_delta = query.step.checked_mul(read_blocks_counter)?;
+        let block_number = match query.direction {
+            Direction::Forward => start_block.checked_add(blocks_delta),
+            Direction::Backward => start_block.checked_sub(blocks_delta),
+        }?;
+        Some(BlockNumber(block_number))
+    })
+}
+
+/// Sends the given data once the sender has room for it.
+pub(crate) async fn send_data(
+    sender: &mut Sender<Data>,
+    data: Data,
+    query_id: QueryId,
+) -> Result<(), DBExecutorError> {
+    // Using poll_fn because Sender::poll_ready is not a future
+    match poll_fn(|cx| sender.poll_ready(cx)).await {
+        Ok(()) => sender
+            .start_send(data)
+            // TODO: consider implement retry mechanism.
+            .map_err(|e| DBExecutorError::SendError { query_id, send_error: e }),
+        Err(e) if e.is_disconnected() => Err(DBExecutorError::ChannelClosed { query_id }),
+        Err(e) => Err(DBExecutorError::SendError { query_id, send_error: e }),
+    }
+}
+
+/// Converts the result of reading a block's data from the storage into the result of the query.
+/// A failure of the storage itself is reported as [`DBExecutorError::DBInternalError`], while data
+/// that is absent from the storage is reported as [`DBExecutorError::BlockNotFound`].
+pub(crate) fn block_data_from_storage_result<T>(
+    storage_result: StorageResult<Option<T>>,
+    block_hash_or_number: BlockHashOrNumber,
     query_id: QueryId,
-) -> Result<u64, DBExecutorError> {
-    let direction_factor: i128 = match query.direction {
-        Direction::Forward => 1,
-        Direction::Backward => -1,
-    };
-    let blocks_delta: i128 = direction_factor * (query.step * read_blocks_counter) as i128;
-    let block_number: i128 = start_block as i128 + blocks_delta;
-    if block_number < 0 || block_number > u64::MAX as i128 {
-        return Err(DBExecutorError::BlockNumberOutOfRange {
-            query,
-            counter: read_blocks_counter,
-            query_id,
-        });
+) -> Result<T, DBExecutorError> {
+    storage_result
+        .map_err(|err| DBExecutorError::DBInternalError { query_id, storage_error: err })?
+        .ok_or(DBExecutorError::BlockNotFound { block_hash_or_number, query_id })
+}
+
+/// Runs `operation`, retrying it up to `max_retries` times while it fails with a transient error
+/// (see [`DBExecutorError::is_transient`]).
+pub(crate) async fn retry_on_transient_error<T>(
+    max_retries: u32,
+    retry_base_delay: Duration,
+    mut operation: impl FnMut() -> Result<T, DBExecutorError>,
+) -> Result<T, DBExecutorError> {
+    let mut retry_index = 0;
+    loop {
+        match operation() {
+            Err(err) if err.is_transient() && retry_index < max_retries => {
+                debug!("Storage operation failed with a transient error, retrying. error: {err:?}");
+                tokio::time::sleep(retry_delay(retry_base_delay, retry_index)).await;
+                retry_index += 1;
+            }
+            result => return result,
+        }
     }
-    Ok(block_number as u64)
+}
+
+/// Returns the time to wait before retrying a failed storage operation for the `retry_index`-th
+/// time (starting from 0).
+fn retry_delay(retry_base_delay: Duration, retry_index: u32) -> Duration {
+    retry_base_delay.saturating_mul(2u32.saturating_pow(retry_index))
 }
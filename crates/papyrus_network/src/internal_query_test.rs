@@ -0,0 +1,79 @@
+use starknet_api::block::BlockNumber;
+
+use crate::{BlockHashOrNumber, DataType, Direction, InternalQuery, Query};
+
+#[test]
+fn from_ascending_range() {
+    assert_eq!(
+        InternalQuery::from_range(BlockNumber(3), BlockNumber(10), 2),
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(3)),
+            direction: Direction::Forward,
+            limit: 4,
+            step: 2,
+        }
+    );
+}
+
+#[test]
+fn from_descending_range() {
+    assert_eq!(
+        InternalQuery::from_range(BlockNumber(10), BlockNumber(4), 3),
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(10)),
+            direction: Direction::Backward,
+            limit: 3,
+            step: 3,
+        }
+    );
+}
+
+#[test]
+fn from_single_block_range() {
+    assert_eq!(
+        InternalQuery::from_range(BlockNumber(7), BlockNumber(7), 1),
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(7)),
+            direction: Direction::Forward,
+            limit: 1,
+            step: 1,
+        }
+    );
+}
+
+#[test]
+fn from_full_range_saturates_limit() {
+    assert_eq!(
+        InternalQuery::from_range(BlockNumber(0), BlockNumber(u64::MAX), 1),
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+            direction: Direction::Forward,
+            limit: u64::MAX,
+            step: 1,
+        }
+    );
+    assert_eq!(InternalQuery::from_range(BlockNumber(u64::MAX), BlockNumber(0), 1).limit, u64::MAX);
+}
+
+#[test]
+fn remaining_after_backward_query() {
+    let query = Query {
+        start_block: BlockNumber(10),
+        direction: Direction::Backward,
+        limit: 5,
+        step: 2,
+        data_type: DataType::SignedBlockHeader,
+    };
+    assert_eq!(
+        query.remaining_after(BlockNumber(8)),
+        Some(Query {
+            start_block: BlockNumber(6),
+            direction: Direction::Backward,
+            limit: 3,
+            step: 2,
+            data_type: DataType::SignedBlockHeader,
+        })
+    );
+    assert_eq!(query.remaining_after(BlockNumber(2)), None);
+    assert_eq!(query.remaining_after(BlockNumber(0)), None);
+}
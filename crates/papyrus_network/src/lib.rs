@@ -5,12 +5,16 @@
 pub mod bin_utils;
 mod converters;
 mod db_executor;
+#[cfg(test)]
+mod internal_query_test;
+mod log_utils;
 pub mod network_manager;
 pub mod protobuf_messages;
 pub mod streamed_bytes;
 #[cfg(test)]
 mod test_utils;
 use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::time::Duration;
@@ -21,11 +25,18 @@ use enum_iterator::Sequence;
 use futures::Stream;
 use libp2p::{PeerId, StreamProtocol};
 use papyrus_config::converters::deserialize_seconds_to_duration;
-use papyrus_config::dumping::{ser_optional_sub_config, ser_param, SerializeConfig};
+use papyrus_config::dumping::{
+    ser_optional_param,
+    ser_optional_sub_config,
+    ser_param,
+    SerializeConfig,
+};
 use papyrus_config::{ParamPath, ParamPrivacyInput, SerializedParam};
 use serde::{Deserialize, Serialize};
 use starknet_api::block::{BlockHash, BlockHeader, BlockNumber, BlockSignature};
 
+use crate::streamed_bytes::OutboundSessionId;
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct NetworkConfig {
     pub tcp_port: u16,
@@ -35,7 +46,13 @@ pub struct NetworkConfig {
     #[serde(deserialize_with = "deserialize_seconds_to_duration")]
     pub idle_connection_timeout: Duration,
     pub header_buffer_size: usize,
+    pub db_executor_concurrency: usize,
+    pub max_blocks_per_query: u64,
+    pub send_response_summary: bool,
+    pub max_inbound_sessions: usize,
+    pub max_pending_events: usize,
     pub peer: Option<PeerAddressConfig>,
+    pub record_queries_to: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -47,8 +64,8 @@ pub struct PeerAddressConfig {
     // one of them to have value
 }
 
-#[derive(Default, Debug, PartialEq, Eq)]
-#[cfg_attr(test, derive(Sequence, Clone, Copy))]
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(test, derive(Sequence))]
 pub enum DataType {
     #[default]
     SignedBlockHeader,
@@ -64,7 +81,37 @@ pub struct Query {
     pub data_type: DataType,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+impl Query {
+    /// Returns a query for the blocks of this query that come after `last_received_block`, or None
+    /// if `last_received_block` is the last block of this query. `last_received_block` should be
+    /// one of the blocks that this query asks for.
+    ///
+    /// Panics if the step of this query is 0.
+    pub fn remaining_after(&self, last_received_block: BlockNumber) -> Option<Query> {
+        let step = self.step as u64;
+        let (distance, next_block_number) = match self.direction {
+            Direction::Forward => (
+                last_received_block.0.checked_sub(self.start_block.0)?,
+                last_received_block.0.checked_add(step)?,
+            ),
+            Direction::Backward => (
+                self.start_block.0.checked_sub(last_received_block.0)?,
+                last_received_block.0.checked_sub(step)?,
+            ),
+        };
+        let num_received_blocks = (distance / step) as usize + 1;
+        let limit = self.limit.checked_sub(num_received_blocks).filter(|limit| *limit > 0)?;
+        Some(Query {
+            start_block: BlockNumber(next_block_number),
+            direction: self.direction,
+            limit,
+            step: self.step,
+            data_type: self.data_type,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Deserialize, Serialize)]
 #[cfg_attr(test, derive(Hash))]
 pub enum Direction {
     #[default]
@@ -80,7 +127,7 @@ pub struct SignedBlockHeader {
 }
 
 // TODO(shahak): Internalize this when we have a mixed behaviour.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
 #[cfg_attr(test, derive(Hash))]
 pub struct InternalQuery {
     pub start_block: BlockHashOrNumber,
@@ -89,26 +136,105 @@ pub struct InternalQuery {
     pub step: u64,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+impl InternalQuery {
+    /// Returns a query for the blocks from `from` through `to` (both inclusive), taking every
+    /// `step`th block. The query goes backward if `to` is below `from`. If the distance between
+    /// them isn't a multiple of `step`, the query ends at the last block before `to`. The limit
+    /// saturates at `u64::MAX`, so a range of all the possible block numbers misses its last block.
+    ///
+    /// Panics if `step` is 0.
+    pub fn from_range(from: BlockNumber, to: BlockNumber, step: u64) -> Self {
+        assert!(step > 0, "The step of a query must be positive.");
+        let (direction, distance) = if from <= to {
+            (Direction::Forward, to.0 - from.0)
+        } else {
+            (Direction::Backward, from.0 - to.0)
+        };
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(from),
+            direction,
+            limit: (distance / step).saturating_add(1),
+            step,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
 #[cfg_attr(test, derive(Hash))]
 pub enum BlockHashOrNumber {
     Hash(BlockHash),
     Number(BlockNumber),
 }
 
+/// Verifies the signatures of block headers received from other peers. Set it on the network
+/// manager to drop headers whose signature is missing or invalid before they reach the subscriber.
+pub trait HeaderVerifier: Send {
+    /// Returns whether the signature signs the given block hash. The block hash is taken as is from
+    /// the received header, so it's up to the subscriber to check that it matches the header's
+    /// other fields.
+    fn verify_signature(&self, block_hash: &BlockHash, signature: &BlockSignature) -> bool;
+}
+
+/// Decides which inbound queries the node serves. Set it on the network manager to refuse queries
+/// (e.g. ranges beyond the node's retention window) without reading them from the storage.
+pub trait QueryFilter: Send {
+    fn should_serve(&self, query: &InternalQuery) -> bool;
+}
+
+/// The reason a peer refused to serve a query.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RejectionReason {
+    /// The query was refused by the peer's [`QueryFilter`].
+    Filtered,
+    /// The peer was already serving the maximal number of inbound sessions.
+    TooManySessions,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HeaderVerificationError {
+    #[error("The data isn't a valid header.")]
+    Malformed,
+    #[error("The header has no signature.")]
+    MissingSignature,
+    #[error("The header has a signature that doesn't match it.")]
+    InvalidSignature,
+}
+
+/// An event on the session of a query that the subscriber sent, which isn't part of the query's
+/// response.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SessionEvent {
+    /// The connection to the peer was closed before the session finished. No more data will arrive
+    /// on this session.
+    SessionClosedByDisconnect { outbound_session_id: OutboundSessionId },
+    /// A header received on this session failed verification and wasn't passed to the subscriber.
+    /// The block number is None if the header is malformed.
+    HeaderRejected {
+        outbound_session_id: OutboundSessionId,
+        block_number: Option<BlockNumber>,
+        error: HeaderVerificationError,
+    },
+    /// The peer refused to serve the query. No data besides Fin will arrive on this session.
+    QueryRejected { outbound_session_id: OutboundSessionId, reason: RejectionReason },
+}
+
 pub struct ResponseReceivers {
     pub signed_headers_receiver: Pin<Box<dyn Stream<Item = Option<SignedBlockHeader>> + Send>>,
+    pub session_events_receiver: Pin<Box<dyn Stream<Item = SessionEvent> + Send>>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Protocol {
     SignedBlockHeader,
+    // TODO: support sending chain tip queries. Currently it's only served to other peers.
+    ChainTip,
 }
 
 impl Protocol {
     pub fn as_str(&self) -> &'static str {
         match self {
             Protocol::SignedBlockHeader => "/starknet/headers/1",
+            Protocol::ChainTip => "/starknet/chain_tip/1",
         }
     }
 }
@@ -153,8 +279,53 @@ impl SerializeConfig for NetworkConfig {
                 "Size of the buffer for headers read from the storage.",
                 ParamPrivacyInput::Public,
             ),
+            ser_param(
+                "db_executor_concurrency",
+                &self.db_executor_concurrency,
+                "Maximal number of inbound queries that read from the storage at the same time. 0 \
+                 is treated as 1.",
+                ParamPrivacyInput::Public,
+            ),
+            ser_param(
+                "max_blocks_per_query",
+                &self.max_blocks_per_query,
+                "Maximal number of blocks that a single inbound query returns. Queries with a \
+                 higher limit are truncated to this number of blocks.",
+                ParamPrivacyInput::Public,
+            ),
+            ser_param(
+                "send_response_summary",
+                &self.send_response_summary,
+                "Whether to end the responses to inbound queries with a summary of the sent data, \
+                 carried in their Fin.",
+                ParamPrivacyInput::Public,
+            ),
+            ser_param(
+                "max_inbound_sessions",
+                &self.max_inbound_sessions,
+                "Maximal number of inbound sessions that are served at the same time. Sessions \
+                 that are opened beyond this number are answered with a rejection and Fin without \
+                 reading from the storage.",
+                ParamPrivacyInput::Public,
+            ),
+            ser_param(
+                "max_pending_events",
+                &self.max_pending_events,
+                "Maximal number of events the network behaviour holds before they're handled. \
+                 Once reached, dials to new peers are dropped. Events of sessions are never \
+                 dropped and may exceed this number.",
+                ParamPrivacyInput::Public,
+            ),
         ]);
         config.extend(ser_optional_sub_config(&self.peer, "peer"));
+        config.extend(ser_optional_param(
+            &self.record_queries_to,
+            PathBuf::from("./data/recorded_queries.jsonl"),
+            "record_queries_to",
+            "If set, every inbound query is appended to this file as a json line, together with \
+             the peer that sent it and the time it was received.",
+            ParamPrivacyInput::Public,
+        ));
         config
     }
 }
@@ -167,7 +338,13 @@ impl Default for NetworkConfig {
             session_timeout: Duration::from_secs(10),
             idle_connection_timeout: Duration::from_secs(10),
             header_buffer_size: 100000,
+            db_executor_concurrency: 100,
+            max_blocks_per_query: 10000,
+            send_response_summary: false,
+            max_inbound_sessions: 1000,
+            max_pending_events: 10000,
             peer: None,
+            record_queries_to: None,
         }
     }
 }
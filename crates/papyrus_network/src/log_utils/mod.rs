@@ -0,0 +1,26 @@
+//! Helpers for displaying peer ids and addresses in logs in a uniform way.
+#[cfg(test)]
+mod test;
+
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+
+/// The number of base58 characters of a peer id that are displayed by [`short_peer_id`].
+pub const SHORT_PEER_ID_LENGTH: usize = 12;
+
+/// Returns the base58 representation of the peer id, truncated to [`SHORT_PEER_ID_LENGTH`]
+/// characters.
+pub fn short_peer_id(peer_id: &PeerId) -> String {
+    let mut peer_id_str = peer_id.to_base58();
+    // Base58 strings are ASCII, so truncating by bytes is safe.
+    if peer_id_str.len() > SHORT_PEER_ID_LENGTH {
+        peer_id_str.truncate(SHORT_PEER_ID_LENGTH);
+        peer_id_str.push_str("...");
+    }
+    peer_id_str
+}
+
+/// Returns the address without its `/p2p/<peer id>` components.
+pub fn dialable_addr(address: &Multiaddr) -> Multiaddr {
+    address.iter().filter(|protocol| !matches!(protocol, Protocol::P2p(_))).collect()
+}
@@ -0,0 +1,30 @@
+use std::str::FromStr;
+
+use libp2p::{Multiaddr, PeerId};
+
+use super::{dialable_addr, short_peer_id, SHORT_PEER_ID_LENGTH};
+
+#[test]
+fn short_peer_id_truncates_base58() {
+    let peer_id = PeerId::random();
+    let full_peer_id = peer_id.to_base58();
+
+    let short = short_peer_id(&peer_id);
+
+    assert_eq!(short, format!("{}...", &full_peer_id[..SHORT_PEER_ID_LENGTH]));
+}
+
+#[test]
+fn dialable_addr_strips_p2p() {
+    let peer_id = PeerId::random();
+    let address = Multiaddr::from_str(&format!("/ip4/127.0.0.1/tcp/10000/p2p/{peer_id}")).unwrap();
+
+    assert_eq!(dialable_addr(&address), Multiaddr::from_str("/ip4/127.0.0.1/tcp/10000").unwrap());
+}
+
+#[test]
+fn dialable_addr_keeps_address_without_p2p() {
+    let address = Multiaddr::from_str("/ip4/127.0.0.1/udp/10001/quic-v1").unwrap();
+
+    assert_eq!(dialable_addr(&address), address);
+}
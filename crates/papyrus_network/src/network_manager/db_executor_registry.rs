@@ -0,0 +1,134 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc::Sender;
+use futures::{Stream, StreamExt};
+use indexmap::IndexMap;
+use libp2p::StreamProtocol;
+
+use crate::db_executor::{DBExecutor, DBExecutorError, Data, DataTransformer, QueryId};
+use crate::{DataType, InternalQuery};
+
+type BoxedDBExecutor = Box<dyn DBExecutor + Send>;
+
+/// A collection of db executors, each serving the queries of a single protocol. Inbound queries
+/// are routed to the db executor registered for the protocol they arrived on.
+///
+/// As a stream, the registry outputs the results of all the registered db executors together with
+/// the protocol of the executor that produced them. The db executors are polled in turns so that a
+/// busy db executor doesn't starve the others. Like a single db executor, the stream is never
+/// exhausted.
+pub(crate) struct DBExecutorRegistry {
+    protocol_to_db_executor: IndexMap<StreamProtocol, (BoxedDBExecutor, DataType)>,
+    // The index of the db executor that is polled first on the next poll.
+    next_db_executor_index: usize,
+    is_paused: bool,
+    data_transformer: Option<Arc<dyn DataTransformer>>,
+}
+
+impl DBExecutorRegistry {
+    pub fn new() -> Self {
+        Self {
+            protocol_to_db_executor: IndexMap::new(),
+            next_db_executor_index: 0,
+            is_paused: false,
+            data_transformer: None,
+        }
+    }
+
+    /// Register a db executor that will serve queries of the given protocol with data of the given
+    /// type. Returns the db executor previously registered for this protocol, if there was one.
+    pub fn register(
+        &mut self,
+        protocol: StreamProtocol,
+        mut db_executor: BoxedDBExecutor,
+        data_type: DataType,
+    ) -> Option<BoxedDBExecutor> {
+        if self.is_paused {
+            db_executor.pause();
+        }
+        if let Some(data_transformer) = &self.data_transformer {
+            db_executor.set_data_transformer(data_transformer.clone());
+        }
+        self.protocol_to_db_executor
+            .insert(protocol, (db_executor, data_type))
+            .map(|(db_executor, _)| db_executor)
+    }
+
+    /// The type of data that the db executor of the given protocol serves. Returns None if there's
+    /// no db executor registered for the protocol.
+    pub fn data_type(&self, protocol: &StreamProtocol) -> Option<DataType> {
+        self.protocol_to_db_executor.get(protocol).map(|(_, data_type)| *data_type)
+    }
+
+    /// Register a query on the db executor of the given protocol. Returns None if there's no db
+    /// executor registered for the protocol.
+    pub fn register_query(
+        &mut self,
+        protocol: &StreamProtocol,
+        query: InternalQuery,
+        sender: Sender<Data>,
+    ) -> Option<QueryId> {
+        let (db_executor, data_type) = self.protocol_to_db_executor.get_mut(protocol)?;
+        Some(db_executor.register_query(query, Box::new(*data_type), sender))
+    }
+
+    /// Register a chain tip query on the db executor of the given protocol. Returns None if there's
+    /// no db executor registered for the protocol.
+    pub fn register_chain_tip_query(
+        &mut self,
+        protocol: &StreamProtocol,
+        sender: Sender<Data>,
+    ) -> Option<QueryId> {
+        let (db_executor, _) = self.protocol_to_db_executor.get_mut(protocol)?;
+        Some(db_executor.register_chain_tip_query(sender))
+    }
+
+    /// Pause all the registered db executors (see [`DBExecutor::pause`]), including the ones that
+    /// are registered while paused.
+    pub fn pause(&mut self) {
+        self.is_paused = true;
+        for (db_executor, _) in self.protocol_to_db_executor.values_mut() {
+            db_executor.pause();
+        }
+    }
+
+    /// Resume all the registered db executors (see [`DBExecutor::resume`]).
+    pub fn resume(&mut self) {
+        self.is_paused = false;
+        for (db_executor, _) in self.protocol_to_db_executor.values_mut() {
+            db_executor.resume();
+        }
+    }
+
+    /// Set the data transformer of all the registered db executors (see
+    /// [`DBExecutor::set_data_transformer`]), including the ones that are registered later.
+    pub fn set_data_transformer(&mut self, data_transformer: Arc<dyn DataTransformer>) {
+        for (db_executor, _) in self.protocol_to_db_executor.values_mut() {
+            db_executor.set_data_transformer(data_transformer.clone());
+        }
+        self.data_transformer = Some(data_transformer);
+    }
+}
+
+impl Stream for DBExecutorRegistry {
+    type Item = (StreamProtocol, Result<QueryId, DBExecutorError>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let unpinned_self = Pin::into_inner(self);
+        let num_db_executors = unpinned_self.protocol_to_db_executor.len();
+        for offset in 0..num_db_executors {
+            let index = (unpinned_self.next_db_executor_index + offset) % num_db_executors;
+            let (protocol, (db_executor, _)) = unpinned_self
+                .protocol_to_db_executor
+                .get_index_mut(index)
+                .expect("The index should be smaller than the number of db executors.");
+            if let Poll::Ready(Some(res)) = db_executor.poll_next_unpin(cx) {
+                unpinned_self.next_db_executor_index = (index + 1) % num_db_executors;
+                return Poll::Ready(Some((protocol.clone(), res)));
+            }
+        }
+        Poll::Pending
+    }
+}
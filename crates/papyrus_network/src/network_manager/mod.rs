@@ -1,31 +1,87 @@
+mod db_executor_registry;
+mod query_metrics;
 mod swarm_trait;
 
 #[cfg(test)]
 mod test;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use futures::channel::mpsc::{Receiver, Sender};
-use futures::future::pending;
+use futures::future::{self, pending};
 use futures::stream::{self, BoxStream, SelectAll};
 use futures::{FutureExt, StreamExt};
 use libp2p::swarm::{DialError, SwarmEvent};
-use libp2p::Swarm;
+use libp2p::{PeerId, StreamProtocol, Swarm};
 use papyrus_storage::StorageReader;
 use prost::Message;
+use serde::{Deserialize, Serialize};
+use starknet_api::block::BlockNumber;
 use tracing::{debug, error, trace};
 
+use self::db_executor_registry::DBExecutorRegistry;
+use self::query_metrics::{record_processed_query, QueryDirection, QueryResult};
 use self::swarm_trait::SwarmTrait;
 use crate::bin_utils::{build_swarm, dial};
-use crate::converters::{Router, RouterError};
-use crate::db_executor::{self, BlockHeaderDBExecutor, DBExecutor, Data, QueryId};
+use crate::converters::{
+    decode_headers_response,
+    decode_inbound_query,
+    encode_response,
+    ReceivedHeadersResponse,
+    ResponseType,
+    Router,
+    RouterError,
+};
+use crate::db_executor::{
+    self,
+    BlockHeaderDBExecutor,
+    DBExecutor,
+    DBExecutorConfig,
+    Data,
+    DataTransformer,
+    QueryId,
+};
 use crate::protobuf_messages::protobuf;
 use crate::streamed_bytes::behaviour::{Behaviour, SessionError};
-use crate::streamed_bytes::{Config, GenericEvent, InboundSessionId};
-use crate::{DataType, NetworkConfig, PeerAddressConfig, Protocol, Query, ResponseReceivers};
+use crate::streamed_bytes::{
+    Config,
+    GenericEvent,
+    InboundSessionId,
+    OutboundSessionId,
+    SessionId,
+    DEFAULT_DIAL_TIMEOUT,
+    DEFAULT_MAX_CONCURRENT_DIALS,
+    DEFAULT_MAX_FRAME_BYTES,
+    DEFAULT_MAX_INFLIGHT_BYTES,
+};
+use crate::{
+    log_utils,
+    DataType,
+    HeaderVerificationError,
+    HeaderVerifier,
+    InternalQuery,
+    NetworkConfig,
+    PeerAddressConfig,
+    Protocol,
+    Query,
+    QueryFilter,
+    RejectionReason,
+    ResponseReceivers,
+    SessionEvent,
+    SignedBlockHeader,
+};
 
-type StreamCollection = SelectAll<BoxStream<'static, (Data, InboundSessionId)>>;
-type SubscriberChannels = (Receiver<Query>, Router);
+type StreamCollection = SelectAll<BoxStream<'static, (Data, InboundSessionId, ResponseType)>>;
+type SubscriberChannels = (Receiver<Query>, Router, Sender<SessionEvent>);
+type ReplayedQueries = SelectAll<BoxStream<'static, (PeerId, InternalQuery)>>;
+
+// Commands are rare and handled right away, so a small buffer is enough.
+const DB_EXECUTOR_COMMAND_BUFFER_SIZE: usize = 16;
 
 #[derive(thiserror::Error, Debug)]
 pub enum NetworkError {
@@ -33,17 +89,68 @@ pub enum NetworkError {
     DialError(#[from] libp2p::swarm::DialError),
 }
 
-pub struct GenericNetworkManager<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> {
+/// A snapshot of the network manager's activity since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ManagerStats {
+    /// Number of sessions opened by other peers that haven't finished yet.
+    pub active_inbound_sessions: usize,
+    /// Number of sessions opened by the sync subscriber's queries that haven't finished yet.
+    pub active_outbound_sessions: usize,
+    /// Total number of bytes sent to other peers as responses to their queries.
+    pub bytes_sent: usize,
+    /// Number of inbound queries that the db executors finished successfully.
+    pub queries_processed: usize,
+    /// Number of inbound queries that failed in the db executors.
+    pub failed_queries: usize,
+    /// Number of inbound and outbound sessions that failed.
+    pub failed_sessions: usize,
+}
+
+/// A command that controls the db executors of a running network manager (see
+/// [`GenericNetworkManager::register_db_executor_controller`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DBExecutorCommand {
+    /// Stop starting new inbound queries. Queries that already started keep running.
+    Pause,
+    /// Start the inbound queries that arrived while paused.
+    Resume,
+}
+
+/// An inbound query as written by the network manager to the file it records queries to. Each line
+/// of the file is a single serialized `RecordedQuery`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RecordedQuery {
+    pub received_at: SystemTime,
+    pub peer_id: PeerId,
+    pub query: InternalQuery,
+}
+
+pub struct GenericNetworkManager<SwarmT: SwarmTrait> {
     swarm: SwarmT,
-    db_executor: DBExecutorT,
+    db_executors: DBExecutorRegistry,
     header_buffer_size: usize,
     query_results_router: StreamCollection,
     sync_subscriber_channels: Option<SubscriberChannels>,
-    query_id_to_inbound_session_id: HashMap<QueryId, InboundSessionId>,
+    query_id_to_inbound_session_id: HashMap<(StreamProtocol, QueryId), InboundSessionId>,
+    // The number of bytes sent so far on each inbound session, reported in its response summary.
+    inbound_session_sent_bytes: HashMap<InboundSessionId, u64>,
+    // The protocol of each outbound session that hasn't finished yet, used to label its metrics.
+    outbound_session_id_to_protocol: HashMap<OutboundSessionId, Protocol>,
     peer: Option<PeerAddressConfig>,
+    stats: ManagerStats,
+    header_verifier: Option<Box<dyn HeaderVerifier>>,
+    // Sends the inbound queries to the thread that writes them to the recording file.
+    query_recorder: Option<std::sync::mpsc::Sender<RecordedQuery>>,
+    query_filter: Option<Box<dyn QueryFilter>>,
+    max_inbound_sessions: Option<usize>,
+    // Inbound sessions that were rejected since there were too many active inbound sessions. They
+    // aren't counted as active.
+    rejected_inbound_sessions: HashSet<InboundSessionId>,
+    replayed_queries: ReplayedQueries,
+    db_executor_commands: Option<Receiver<DBExecutorCommand>>,
 }
 
-impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecutorT, SwarmT> {
+impl<SwarmT: SwarmTrait> GenericNetworkManager<SwarmT> {
     pub async fn run(mut self) -> Result<(), NetworkError> {
         if let Some(peer) = self.peer.clone() {
             debug!("Starting network manager connected to peer: {peer:?}");
@@ -52,51 +159,243 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
             debug!("Starting network manager not connected to any peer.");
         }
         loop {
+            let (query_receiver, response_senders) = match self.sync_subscriber_channels.as_mut() {
+                Some((query_receiver, response_senders, _)) => {
+                    (Some(query_receiver), Some(response_senders))
+                }
+                None => (None, None),
+            };
             tokio::select! {
-                Some(event) = self.swarm.next() => self.handle_swarm_event(event),
-                Some(res) = self.db_executor.next() => self.handle_db_executor_result(res),
+                Some(event) = Self::next_swarm_event(&mut self.swarm, response_senders) => self.handle_swarm_event(event),
+                Some((protocol, res)) = self.db_executors.next() => self.handle_db_executor_result(protocol, res),
                 Some(res) = self.query_results_router.next() => self.handle_query_result_routing_to_other_peer(res),
-                Some(res) = self.sync_subscriber_channels.as_mut()
-                .map(|(query_receiver, _)| query_receiver.next().boxed())
+                Some(res) = query_receiver
+                .map(|query_receiver| query_receiver.next().boxed())
                 .unwrap_or(pending().boxed()) => self.handle_sync_subscriber_query(res),
+                Some((peer_id, query)) = self.replayed_queries.next() => self.send_replayed_query(peer_id, query),
+                Some(command) = self.db_executor_commands.as_mut()
+                .map(|command_receiver| command_receiver.next().boxed())
+                .unwrap_or(pending().boxed()) => self.handle_db_executor_command(command),
             }
         }
     }
 
+    /// Wait for the next event of the swarm. If there's a subscriber, the swarm is polled only
+    /// once the subscriber has room for the data of the event, so that received data is never
+    /// dropped. While the swarm isn't polled, the connection handlers stop reading from their
+    /// outbound sessions, which applies backpressure on the peers sending the data.
+    async fn next_swarm_event(
+        swarm: &mut SwarmT,
+        response_senders: Option<&mut Router>,
+    ) -> Option<swarm_trait::Event> {
+        if let Some(response_senders) = response_senders {
+            response_senders.ready().await;
+        }
+        swarm.next().await
+    }
+
     pub(self) fn generic_new(
         swarm: SwarmT,
-        db_executor: DBExecutorT,
+        db_executor: impl DBExecutor + Send + 'static,
         header_buffer_size: usize,
         peer: Option<PeerAddressConfig>,
     ) -> Self {
+        let mut db_executors = DBExecutorRegistry::new();
+        db_executors.register(
+            Protocol::SignedBlockHeader.into(),
+            Box::new(db_executor),
+            DataType::SignedBlockHeader,
+        );
         Self {
             swarm,
-            db_executor,
+            db_executors,
             header_buffer_size,
             query_results_router: StreamCollection::new(),
             sync_subscriber_channels: None,
             query_id_to_inbound_session_id: HashMap::new(),
+            inbound_session_sent_bytes: HashMap::new(),
+            outbound_session_id_to_protocol: HashMap::new(),
             peer,
+            stats: ManagerStats::default(),
+            header_verifier: None,
+            query_recorder: None,
+            query_filter: None,
+            max_inbound_sessions: None,
+            rejected_inbound_sessions: HashSet::new(),
+            replayed_queries: ReplayedQueries::new(),
+            db_executor_commands: None,
         }
     }
 
+    pub fn stats(&self) -> ManagerStats {
+        self.stats
+    }
+
+    /// Verify the signatures of headers received from other peers with the given verifier. Headers
+    /// that fail verification, including malformed ones, are reported to the subscriber as
+    /// [`SessionEvent::HeaderRejected`] instead of being passed to it. Without a verifier,
+    /// malformed headers are dropped.
+    pub fn set_header_verifier(&mut self, header_verifier: Box<dyn HeaderVerifier>) {
+        self.header_verifier = Some(header_verifier);
+    }
+
+    /// Serve only the inbound queries that the given filter accepts. Rejected queries are answered
+    /// with a rejection and Fin without reaching the db executors.
+    pub fn set_query_filter(&mut self, query_filter: Box<dyn QueryFilter>) {
+        self.query_filter = Some(query_filter);
+    }
+
+    /// Serve at most the given number of inbound sessions at the same time. Inbound sessions that
+    /// are opened beyond this number are answered with a rejection and Fin without reaching the db
+    /// executors.
+    pub fn set_max_inbound_sessions(&mut self, max_inbound_sessions: usize) {
+        self.max_inbound_sessions = Some(max_inbound_sessions);
+    }
+
+    /// Append every inbound query to the file at the given path as a json line (see
+    /// [`RecordedQuery`]). The file is created if it doesn't exist. The queries are written by a
+    /// dedicated thread, so that writing them doesn't block the network manager.
+    pub fn record_queries_to(&mut self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("query_recorder".to_owned())
+            .spawn(move || write_recorded_queries(receiver, BufWriter::new(file)))?;
+        self.query_recorder = Some(sender);
+        Ok(())
+    }
+
+    /// Send the queries recorded in the file at the given path (see [`Self::record_queries_to`]) to
+    /// the given peer, in the order they were recorded. If `preserve_timing` is set, the queries
+    /// are sent with the same intervals between them as when they were recorded. Otherwise, they
+    /// are sent as fast as possible. The queries are sent while the network manager runs.
+    pub fn replay_queries(
+        &mut self,
+        path: &Path,
+        target_peer: PeerId,
+        preserve_timing: bool,
+    ) -> io::Result<()> {
+        let recorded_queries = BufReader::new(File::open(path)?)
+            .lines()
+            .map(|line| Ok(serde_json::from_str::<RecordedQuery>(&line?)?))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut previous_received_at = recorded_queries.first().map(|query| query.received_at);
+        let delays_and_queries = recorded_queries
+            .into_iter()
+            .map(|RecordedQuery { received_at, query, .. }| {
+                let delay = match previous_received_at.replace(received_at) {
+                    Some(previous_received_at) if preserve_timing => {
+                        received_at.duration_since(previous_received_at).unwrap_or_default()
+                    }
+                    _ => Duration::ZERO,
+                };
+                (delay, query)
+            })
+            .collect::<Vec<_>>();
+        self.replayed_queries.push(
+            stream::iter(delays_and_queries)
+                .then(move |(delay, query)| async move {
+                    if !delay.is_zero() {
+                        tokio::time::sleep(delay).await;
+                    }
+                    (target_peer, query)
+                })
+                .boxed(),
+        );
+        Ok(())
+    }
+
+    /// Send the part of `original_query` that comes after `last_received_block` to the given peer.
+    /// Use it to continue the session of a query that failed (e.g. on disconnect) without receiving
+    /// the blocks that were already received again. Does nothing if `last_received_block` is the
+    /// last block of the query.
+    pub fn resume_session(
+        &mut self,
+        original_query: Query,
+        last_received_block: BlockNumber,
+        peer_id: PeerId,
+    ) {
+        let Some(query) = original_query.remaining_after(last_received_block) else {
+            debug!(
+                "Query {original_query:?} ends at block {last_received_block:?}. Nothing to \
+                 resume."
+            );
+            return;
+        };
+        debug!("Resuming query {original_query:?} from block {:?}.", query.start_block);
+        self.send_subscriber_query_to_peer(query, peer_id);
+    }
+
+    /// Register a db executor that will serve inbound queries of the given protocol. The protocol
+    /// should also appear in the supported inbound protocols of the swarm's behaviour. Replaces the
+    /// db executor previously registered for this protocol, if there was one.
+    pub fn register_db_executor(
+        &mut self,
+        protocol: StreamProtocol,
+        db_executor: impl DBExecutor + Send + 'static,
+        data_type: DataType,
+    ) {
+        if self.db_executors.register(protocol.clone(), Box::new(db_executor), data_type).is_some()
+        {
+            debug!("Replaced the db executor of protocol {protocol:?}.");
+        }
+    }
+
+    /// Stop the db executors from starting new inbound queries, e.g. to prioritize other reads
+    /// from the storage. Queries that already started keep running, and the sessions of the
+    /// queries that arrive while paused stay open until the queries start on resume.
+    pub fn pause_db_executors(&mut self) {
+        debug!("Pausing the db executors.");
+        self.db_executors.pause();
+    }
+
+    /// Start the inbound queries that arrived since the db executors were paused.
+    pub fn resume_db_executors(&mut self) {
+        debug!("Resuming the db executors.");
+        self.db_executors.resume();
+    }
+
+    /// Pass the data of every block that the db executors read for inbound queries through the
+    /// given transformer before it's sent, e.g. to attach additional metadata to it. Applies only
+    /// to queries that arrive after this call.
+    pub fn set_data_transformer(&mut self, data_transformer: Arc<dyn DataTransformer>) {
+        self.db_executors.set_data_transformer(data_transformer);
+    }
+
+    /// Returns a sender of commands that pause and resume the db executors while the network
+    /// manager runs.
+    pub fn register_db_executor_controller(&mut self) -> Sender<DBExecutorCommand> {
+        let (sender, command_receiver) =
+            futures::channel::mpsc::channel(DB_EXECUTOR_COMMAND_BUFFER_SIZE);
+        self.db_executor_commands = Some(command_receiver);
+        sender
+    }
+
     pub fn register_subscriber(
         &mut self,
         protocols: Vec<Protocol>,
     ) -> (Sender<Query>, ResponseReceivers) {
         let (sender, query_receiver) = futures::channel::mpsc::channel(self.header_buffer_size);
         let mut router = Router::new(protocols, self.header_buffer_size);
-        let response_receiver = ResponseReceivers::new(router.get_recievers());
-        self.sync_subscriber_channels = Some((query_receiver, router));
+        let (session_events_sender, session_events_receiver) =
+            futures::channel::mpsc::channel(self.header_buffer_size);
+        let response_receiver =
+            ResponseReceivers::new(router.get_recievers(), session_events_receiver);
+        self.sync_subscriber_channels = Some((query_receiver, router, session_events_sender));
         (sender, response_receiver)
     }
 
     fn handle_swarm_event(&mut self, event: SwarmEvent<GenericEvent<SessionError>>) {
         match event {
-            SwarmEvent::ConnectionEstablished { .. } => {
-                debug!("Connected to a peer!");
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                debug!(
+                    "Connected to peer {} at {}",
+                    log_utils::short_peer_id(&peer_id),
+                    log_utils::dialable_addr(endpoint.get_remote_address())
+                );
             }
             SwarmEvent::NewListenAddr { .. }
+            | SwarmEvent::Dialing { .. }
             | SwarmEvent::IncomingConnection { .. }
             | SwarmEvent::ConnectionClosed { .. } => {}
             SwarmEvent::Behaviour(event) => {
@@ -114,6 +413,14 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
                      {endpoint:?}"
                 );
             }
+            SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => {
+                // The sessions that waited for this connection fail on their own, so there's
+                // nothing to do besides reporting the error.
+                error!(
+                    "Outgoing connection error. connection id: {connection_id:?}, peer id: \
+                     {peer_id:?}, error: {error:?}"
+                );
+            }
             SwarmEvent::IncomingConnectionError {
                 connection_id,
                 local_addr,
@@ -133,20 +440,37 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
         }
     }
 
+    fn handle_db_executor_command(&mut self, command: DBExecutorCommand) {
+        match command {
+            DBExecutorCommand::Pause => self.pause_db_executors(),
+            DBExecutorCommand::Resume => self.resume_db_executors(),
+        }
+    }
+
     fn handle_db_executor_result(
         &mut self,
+        protocol: StreamProtocol,
         res: Result<db_executor::QueryId, db_executor::DBExecutorError>,
     ) {
+        record_processed_query(
+            protocol.as_ref(),
+            QueryDirection::Inbound,
+            QueryResult::from_db_executor_result(&res),
+        );
         match res {
             Ok(query_id) => {
                 // TODO: in case we want to do bookkeeping, this is the place.
-                debug!("Query completed successfully. query_id: {query_id:?}");
+                self.stats.queries_processed += 1;
+                debug!(
+                    "Query completed successfully. query_id: {query_id:?}, protocol: {protocol:?}"
+                );
             }
             Err(err) => {
+                self.stats.failed_queries += 1;
                 if err.should_log_in_error_level() {
-                    error!("Query failed. error: {err:?}");
+                    error!("Query failed. error: {err:?}, protocol: {protocol:?}");
                 } else {
-                    debug!("Query failed. error: {err:?}");
+                    debug!("Query failed. error: {err:?}, protocol: {protocol:?}");
                 }
             }
         };
@@ -157,29 +481,119 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
             GenericEvent::NewInboundSession {
                 query,
                 inbound_session_id,
-                peer_id: _,
-                protocol_name: _,
+                peer_id,
+                protocol_name,
             } => {
                 trace!(
-                    "Received new inbound query: {query:?} for session id: {inbound_session_id:?}"
+                    "Received new inbound query: {query:?} for session id: {inbound_session_id:?} \
+                     on protocol {protocol_name:?}"
                 );
+                let response_type = if protocol_name == StreamProtocol::from(Protocol::ChainTip) {
+                    ResponseType::ChainTip
+                } else if let Some(data_type) = self.db_executors.data_type(&protocol_name) {
+                    ResponseType::BlockData(data_type)
+                } else {
+                    // TODO: close the inbound session once the swarm supports it.
+                    error!(
+                        "No db executor is registered for protocol {protocol_name:?}. Ignoring \
+                         inbound session {inbound_session_id:?}."
+                    );
+                    return;
+                };
+                if self
+                    .max_inbound_sessions
+                    .is_some_and(|max| self.stats.active_inbound_sessions >= max)
+                {
+                    debug!(
+                        "Rejected inbound session {inbound_session_id:?} since there are already \
+                         {} active inbound sessions. Sending rejection and Fin.",
+                        self.stats.active_inbound_sessions
+                    );
+                    record_processed_query(
+                        protocol_name.as_ref(),
+                        QueryDirection::Inbound,
+                        QueryResult::Rejected,
+                    );
+                    self.rejected_inbound_sessions.insert(inbound_session_id);
+                    self.reject_inbound_session(
+                        inbound_session_id,
+                        response_type,
+                        RejectionReason::TooManySessions,
+                    );
+                    return;
+                }
+                // TODO: consider moving conversion out of network manager.
+                let internal_query = match decode_inbound_query(response_type, &query) {
+                    Ok(internal_query) => internal_query,
+                    Err(e) => {
+                        debug!(
+                            "Failed to decode inbound query of session {inbound_session_id:?} on \
+                             protocol {protocol_name:?}: {e:?}. Sending Fin."
+                        );
+                        self.stats.active_inbound_sessions += 1;
+                        self.send_fin_to_inbound_session(inbound_session_id, response_type);
+                        return;
+                    }
+                };
+                if let Some(internal_query) = internal_query {
+                    self.record_inbound_query(peer_id, internal_query);
+                    if self
+                        .query_filter
+                        .as_ref()
+                        .is_some_and(|query_filter| !query_filter.should_serve(&internal_query))
+                    {
+                        debug!(
+                            "Rejected inbound query {internal_query:?} of session \
+                             {inbound_session_id:?}. Sending rejection and Fin."
+                        );
+                        self.stats.active_inbound_sessions += 1;
+                        self.reject_inbound_session(
+                            inbound_session_id,
+                            response_type,
+                            RejectionReason::Filtered,
+                        );
+                        return;
+                    }
+                }
                 let (sender, receiver) = futures::channel::mpsc::channel(self.header_buffer_size);
                 // TODO: use query id for bookkeeping.
-                // TODO: consider moving conversion out of network manager.
-                let internal_query = protobuf::BlockHeadersRequest::decode(&query[..])
-                    .expect("failed to decode protobuf BlockHeadersRequest")
-                    .try_into()
-                    .expect("failed to convert BlockHeadersRequest");
-                let query_id = self.db_executor.register_query(
-                    internal_query,
-                    DataType::SignedBlockHeader,
-                    sender,
-                );
-                self.query_id_to_inbound_session_id.insert(query_id, inbound_session_id);
+                let (db_executor_protocol, query_id) = match internal_query {
+                    Some(internal_query) => {
+                        let query_id = self
+                            .db_executors
+                            .register_query(&protocol_name, internal_query, sender)
+                            .expect(
+                                "The db executor of the protocol should have been found above.",
+                            );
+                        (protocol_name, query_id)
+                    }
+                    // The chain tip is read by the db executor of the block headers, which is
+                    // always registered.
+                    None => {
+                        let headers_protocol = StreamProtocol::from(Protocol::SignedBlockHeader);
+                        let query_id = self
+                            .db_executors
+                            .register_chain_tip_query(&headers_protocol, sender)
+                            .expect("The db executor of the block headers should be registered.");
+                        (headers_protocol, query_id)
+                    }
+                };
+                self.stats.active_inbound_sessions += 1;
+                self.query_id_to_inbound_session_id
+                    .insert((db_executor_protocol, query_id), inbound_session_id);
+                // Fin is sent once the db executor drops the sender, even if the query didn't
+                // produce any data (e.g. none of the queried blocks are in the storage). If the db
+                // executor ended the data with a summary, the summary is sent instead of the Fin.
+                let mut sent_summary = false;
                 self.query_results_router.push(
                     receiver
                         .chain(stream::once(async { Data::Fin }))
-                        .map(move |data| (data, inbound_session_id))
+                        .filter(move |data| {
+                            let is_redundant_fin = sent_summary && matches!(data, Data::Fin);
+                            sent_summary |= matches!(data, Data::FinWithSummary { .. });
+                            future::ready(!is_redundant_fin)
+                        })
+                        .map(move |data| (data, inbound_session_id, response_type))
                         .boxed(),
                 );
             }
@@ -188,9 +602,63 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
                     "Received data from peer for session id: {outbound_session_id:?}. sending to \
                      sync subscriber."
                 );
-                if let Some((_, response_senders)) = self.sync_subscriber_channels.as_mut() {
+                let maybe_signed_header = match decode_headers_response(&data) {
+                    Ok(ReceivedHeadersResponse::Header(maybe_signed_header)) => maybe_signed_header,
+                    Ok(ReceivedHeadersResponse::Rejection(reason)) => {
+                        debug!(
+                            "Peer rejected the query of outbound session {outbound_session_id:?}. \
+                             reason: {reason:?}"
+                        );
+                        self.report_session_event_to_subscriber(SessionEvent::QueryRejected {
+                            outbound_session_id,
+                            reason,
+                        });
+                        return;
+                    }
+                    Err(e) if self.header_verifier.is_some() => {
+                        debug!(
+                            "Rejected malformed header received on outbound session \
+                             {outbound_session_id:?}: {e:?}"
+                        );
+                        self.report_session_event_to_subscriber(SessionEvent::HeaderRejected {
+                            outbound_session_id,
+                            block_number: None,
+                            error: HeaderVerificationError::Malformed,
+                        });
+                        return;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to decode header received on outbound session \
+                             {outbound_session_id:?}. Dropping data. error: {e:?}"
+                        );
+                        return;
+                    }
+                };
+                if let (Some(header_verifier), Some(signed_header)) =
+                    (self.header_verifier.as_ref(), maybe_signed_header.as_ref())
+                {
+                    if let Err(error) =
+                        verify_received_header(header_verifier.as_ref(), signed_header)
+                    {
+                        let block_number = signed_header.block_header.block_number;
+                        debug!(
+                            "Rejected header of block {block_number:?} received on outbound \
+                             session {outbound_session_id:?}: {error}"
+                        );
+                        self.report_session_event_to_subscriber(SessionEvent::HeaderRejected {
+                            outbound_session_id,
+                            block_number: Some(block_number),
+                            error,
+                        });
+                        return;
+                    }
+                }
+                if let Some((_, response_senders, _)) = self.sync_subscriber_channels.as_mut() {
                     // TODO: once we have more protocols map session id to protocol.
-                    match response_senders.try_send(Protocol::SignedBlockHeader, data) {
+                    match response_senders
+                        .try_send(Protocol::SignedBlockHeader, maybe_signed_header)
+                    {
                         Err(RouterError::NoSenderForProtocol { protocol }) => {
                             error!(
                                 "The response sender does't support protocol: {protocol:?}. \
@@ -201,10 +669,10 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
                             if e.is_disconnected() {
                                 panic!("Receiver was dropped. This should never happen.")
                             } else if e.is_full() {
-                                error!(
-                                    "Receiver buffer is full. Dropping data. outbound_session_id: \
-                                     {outbound_session_id:?}"
-                                );
+                                panic!(
+                                    "Receiver buffer is full although the swarm is polled only \
+                                     when it has room. This should never happen."
+                                )
                             }
                         }
                         Ok(()) => {}
@@ -212,30 +680,142 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
                 }
             }
             GenericEvent::SessionFailed { session_id, error } => {
-                debug!("Session {session_id:?} failed on {error:?}");
+                debug!("Session {session_id} failed on {error:?}");
+                if let Some(protocol) = self.remove_outbound_session_protocol(session_id) {
+                    record_processed_query(
+                        protocol.as_str(),
+                        QueryDirection::Outbound,
+                        QueryResult::from_session_error(&error),
+                    );
+                }
+                self.stats.failed_sessions += 1;
+                self.mark_session_as_finished(session_id);
                 // TODO: Handle reputation and retry.
+                if let (
+                    SessionId::OutboundSessionId(outbound_session_id),
+                    SessionError::ConnectionClosed,
+                ) = (session_id, error)
+                {
+                    self.report_session_event_to_subscriber(
+                        SessionEvent::SessionClosedByDisconnect { outbound_session_id },
+                    );
+                }
             }
             GenericEvent::SessionFinishedSuccessfully { session_id } => {
-                debug!("Session completed successfully. session_id: {session_id:?}");
+                debug!("Session {session_id} completed successfully.");
+                if let Some(protocol) = self.remove_outbound_session_protocol(session_id) {
+                    record_processed_query(
+                        protocol.as_str(),
+                        QueryDirection::Outbound,
+                        QueryResult::Ok,
+                    );
+                }
+                self.mark_session_as_finished(session_id);
             }
         }
     }
 
-    fn handle_query_result_routing_to_other_peer(&mut self, res: (Data, InboundSessionId)) {
+    fn handle_query_result_routing_to_other_peer(
+        &mut self,
+        res: (Data, InboundSessionId, ResponseType),
+    ) {
         if self.query_results_router.is_empty() {
             // We're done handling all the queries we had and the stream is exhausted.
             // Creating a new stream collection to process new queries.
             self.query_results_router = StreamCollection::new();
         }
-        let (data, inbound_session_id) = res;
-        let mut data_bytes = vec![];
-        <Data as TryInto<protobuf::BlockHeadersResponse>>::try_into(data)
-            .expect("DB returned data for query that is not expected by this protocol")
-            .encode(&mut data_bytes)
-            .expect("failed to convert data to bytes");
-        self.swarm.send_data(data_bytes, inbound_session_id).unwrap_or_else(|e| {
-            error!("Failed to send data to peer. Session id not found error: {e:?}");
-        })
+        let (mut data, inbound_session_id, response_type) = res;
+        let sent_bytes = self.inbound_session_sent_bytes.entry(inbound_session_id).or_default();
+        if let Data::FinWithSummary { summary } = &mut data {
+            summary.total_bytes = *sent_bytes;
+        }
+        let is_fin = matches!(data, Data::Fin | Data::FinWithSummary { .. });
+        let data_bytes = match encode_response(response_type, data) {
+            Ok(data_bytes) => data_bytes,
+            Err(e) => {
+                error!(
+                    "DB returned data for inbound session {inbound_session_id:?} that is not \
+                     expected by its protocol. Dropping data. error: {e:?}"
+                );
+                return;
+            }
+        };
+        let data_len = data_bytes.len();
+        if is_fin {
+            self.inbound_session_sent_bytes.remove(&inbound_session_id);
+        } else {
+            *sent_bytes += u64::try_from(data_len).expect("Failed converting usize to u64");
+        }
+        match self.swarm.send_data(data_bytes, inbound_session_id) {
+            Ok(()) => self.stats.bytes_sent += data_len,
+            Err(e) => error!("Failed to send data to peer. Session id not found error: {e:?}"),
+        }
+    }
+
+    fn send_fin_to_inbound_session(
+        &mut self,
+        inbound_session_id: InboundSessionId,
+        response_type: ResponseType,
+    ) {
+        self.query_results_router.push(
+            stream::once(async move { (Data::Fin, inbound_session_id, response_type) }).boxed(),
+        );
+    }
+
+    // Send a rejection and then Fin instead of the data of the session's query.
+    fn reject_inbound_session(
+        &mut self,
+        inbound_session_id: InboundSessionId,
+        response_type: ResponseType,
+        reason: RejectionReason,
+    ) {
+        self.query_results_router.push(
+            stream::iter([Data::Rejection { reason }, Data::Fin])
+                .map(move |data| (data, inbound_session_id, response_type))
+                .boxed(),
+        );
+    }
+
+    /// Returns the protocol of the given session if it's an outbound session we sent a query on,
+    /// and forgets it since the session finished.
+    fn remove_outbound_session_protocol(&mut self, session_id: SessionId) -> Option<Protocol> {
+        let SessionId::OutboundSessionId(outbound_session_id) = session_id else {
+            return None;
+        };
+        self.outbound_session_id_to_protocol.remove(&outbound_session_id)
+    }
+
+    fn mark_session_as_finished(&mut self, session_id: SessionId) {
+        if let SessionId::InboundSessionId(inbound_session_id) = session_id {
+            if self.rejected_inbound_sessions.remove(&inbound_session_id) {
+                return;
+            }
+        }
+        let active_sessions = if session_id.is_inbound() {
+            &mut self.stats.active_inbound_sessions
+        } else {
+            &mut self.stats.active_outbound_sessions
+        };
+        *active_sessions = active_sessions.saturating_sub(1);
+    }
+
+    fn record_inbound_query(&mut self, peer_id: PeerId, query: InternalQuery) {
+        let Some(query_recorder) = self.query_recorder.as_ref() else {
+            return;
+        };
+        let recorded_query = RecordedQuery { received_at: SystemTime::now(), peer_id, query };
+        if query_recorder.send(recorded_query).is_err() {
+            error!("Failed to record inbound query {query:?}: the recording thread has stopped.");
+        }
+    }
+
+    fn report_session_event_to_subscriber(&mut self, session_event: SessionEvent) {
+        let Some((_, _, session_events_sender)) = self.sync_subscriber_channels.as_mut() else {
+            return;
+        };
+        if let Err(e) = session_events_sender.try_send(session_event) {
+            error!("Failed to report session event {session_event:?} to sync subscriber: {e:?}");
+        }
     }
 
     fn handle_sync_subscriber_query(&mut self, query: Query) {
@@ -245,24 +825,77 @@ impl<DBExecutorT: DBExecutor, SwarmT: SwarmTrait> GenericNetworkManager<DBExecut
             .expect("Cannot send query without peer")
             // TODO: get peer id from swarm after dial id not received in config.
             .peer_id;
+        self.send_subscriber_query_to_peer(query, peer_id);
+    }
+
+    fn send_subscriber_query_to_peer(&mut self, query: Query, peer_id: PeerId) {
         let mut query_bytes = vec![];
         <Query as Into<protobuf::BlockHeadersRequest>>::into(query)
             .encode(&mut query_bytes)
             .expect("failed to convert query to bytes");
-        match self.swarm.send_query(query_bytes, peer_id, Protocol::SignedBlockHeader) {
-            Ok(outbound_session_id) => {
-                debug!(
-                    "Sent query to peer. peer_id: {peer_id:?}, outbound_session_id: \
-                     {outbound_session_id:?}"
-                );
+        self.send_query_to_peer(query_bytes, peer_id);
+    }
+
+    fn send_replayed_query(&mut self, peer_id: PeerId, query: InternalQuery) {
+        debug!("Replaying query {query:?} to peer {peer_id:?}.");
+        let mut query_bytes = vec![];
+        protobuf::BlockHeadersRequest::from(query)
+            .encode(&mut query_bytes)
+            .expect("failed to convert query to bytes");
+        self.send_query_to_peer(query_bytes, peer_id);
+    }
+
+    fn send_query_to_peer(&mut self, query_bytes: Vec<u8>, peer_id: PeerId) {
+        let protocol = Protocol::SignedBlockHeader;
+        let outbound_session_id = self.swarm.send_query(query_bytes, peer_id, protocol);
+        self.outbound_session_id_to_protocol.insert(outbound_session_id, protocol);
+        self.stats.active_outbound_sessions += 1;
+        debug!(
+            "Sent query to peer. peer_id: {peer_id:?}, outbound_session_id: \
+             {outbound_session_id:?}"
+        );
+    }
+}
+
+pub type NetworkManager = GenericNetworkManager<Swarm<Behaviour>>;
+
+/// Verify the signatures of a header received from another peer.
+fn verify_received_header(
+    header_verifier: &dyn HeaderVerifier,
+    signed_header: &SignedBlockHeader,
+) -> Result<(), HeaderVerificationError> {
+    let SignedBlockHeader { block_header, signatures } = signed_header;
+    if signatures.is_empty() {
+        return Err(HeaderVerificationError::MissingSignature);
+    }
+    if !signatures
+        .iter()
+        .all(|signature| header_verifier.verify_signature(&block_header.block_hash, signature))
+    {
+        return Err(HeaderVerificationError::InvalidSignature);
+    }
+    Ok(())
+}
+
+/// Write the recorded queries as json lines until the network manager that sends them is dropped.
+/// The writer is flushed whenever there are no more queries waiting to be written.
+fn write_recorded_queries(
+    receiver: std::sync::mpsc::Receiver<RecordedQuery>,
+    mut writer: impl Write,
+) {
+    while let Ok(recorded_query) = receiver.recv() {
+        for recorded_query in std::iter::once(recorded_query).chain(receiver.try_iter()) {
+            let line = serde_json::to_string(&recorded_query).expect("failed to serialize query");
+            if let Err(e) = writeln!(writer, "{line}") {
+                error!("Failed to record inbound query {:?}: {e:?}", recorded_query.query);
             }
-            Err(e) => error!("Failed to send query to peer. Peer not connected error: {e:?}"),
+        }
+        if let Err(e) = writer.flush() {
+            error!("Failed to flush the recorded queries: {e:?}");
         }
     }
 }
 
-pub type NetworkManager = GenericNetworkManager<BlockHeaderDBExecutor, Swarm<Behaviour>>;
-
 impl NetworkManager {
     pub fn new(config: NetworkConfig, storage_reader: StorageReader) -> Self {
         let NetworkConfig {
@@ -271,7 +904,13 @@ impl NetworkManager {
             session_timeout,
             idle_connection_timeout,
             header_buffer_size,
+            db_executor_concurrency,
+            max_blocks_per_query,
+            send_response_summary,
+            max_inbound_sessions,
+            max_pending_events,
             peer,
+            record_queries_to,
         } = config;
 
         let listen_addresses = vec![
@@ -284,12 +923,37 @@ impl NetworkManager {
             idle_connection_timeout,
             Behaviour::new(Config {
                 session_timeout,
-                supported_inbound_protocols: vec![Protocol::SignedBlockHeader.into()],
+                supported_inbound_protocols: vec![
+                    Protocol::SignedBlockHeader.into(),
+                    Protocol::ChainTip.into(),
+                ],
+                max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+                max_inflight_bytes: DEFAULT_MAX_INFLIGHT_BYTES,
+                dial_timeout: DEFAULT_DIAL_TIMEOUT,
+                max_concurrent_dials: DEFAULT_MAX_CONCURRENT_DIALS,
+                max_pending_events: Some(max_pending_events),
+                pending_events_overflow_policy: Default::default(),
+                compress_data: false,
             }),
         );
 
-        let db_executor = BlockHeaderDBExecutor::new(storage_reader);
-        Self::generic_new(swarm, db_executor, header_buffer_size, peer)
+        let db_executor = BlockHeaderDBExecutor::new(
+            storage_reader,
+            DBExecutorConfig {
+                concurrency: db_executor_concurrency,
+                max_blocks_per_query,
+                send_response_summary,
+                ..Default::default()
+            },
+        );
+        let mut network_manager = Self::generic_new(swarm, db_executor, header_buffer_size, peer);
+        network_manager.set_max_inbound_sessions(max_inbound_sessions);
+        if let Some(path) = record_queries_to {
+            network_manager
+                .record_queries_to(&path)
+                .unwrap_or_else(|e| panic!("Failed to open query recording file {path:?}: {e:?}"));
+        }
+        network_manager
     }
 
     // TODO(shahak): Move this to the constructor and add the address to the config once we have
@@ -0,0 +1,79 @@
+use metrics::increment_counter;
+
+use crate::db_executor::{DBExecutorError, QueryId};
+use crate::streamed_bytes::behaviour::SessionError;
+
+/// The number of queries the network manager finished processing. Labeled by [`PROTOCOL_LABEL`],
+/// [`DIRECTION_LABEL`] and [`RESULT_LABEL`].
+pub(crate) const PROCESSED_QUERIES: &str = "papyrus_network_processed_queries";
+
+/// The name of the protocol of the query.
+pub(crate) const PROTOCOL_LABEL: &str = "protocol";
+/// `inbound` for queries received from other peers and `outbound` for queries sent to them.
+pub(crate) const DIRECTION_LABEL: &str = "direction";
+/// One of `ok`, `not_found`, `timeout`, `rejected` or `error`.
+pub(crate) const RESULT_LABEL: &str = "result";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum QueryDirection {
+    Inbound,
+    Outbound,
+}
+
+impl QueryDirection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inbound => "inbound",
+            Self::Outbound => "outbound",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum QueryResult {
+    Ok,
+    NotFound,
+    Timeout,
+    Rejected,
+    Error,
+}
+
+impl QueryResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::NotFound => "not_found",
+            Self::Timeout => "timeout",
+            Self::Rejected => "rejected",
+            Self::Error => "error",
+        }
+    }
+
+    pub(crate) fn from_db_executor_result(res: &Result<QueryId, DBExecutorError>) -> Self {
+        match res {
+            Ok(_) => Self::Ok,
+            Err(DBExecutorError::BlockNotFound { .. }) => Self::NotFound,
+            Err(_) => Self::Error,
+        }
+    }
+
+    pub(crate) fn from_session_error(error: &SessionError) -> Self {
+        match error {
+            SessionError::Timeout { .. } | SessionError::DialTimeout { .. } => Self::Timeout,
+            _ => Self::Error,
+        }
+    }
+}
+
+pub(crate) fn record_processed_query(
+    protocol: &str,
+    direction: QueryDirection,
+    result: QueryResult,
+) {
+    increment_counter!(
+        PROCESSED_QUERIES,
+        PROTOCOL_LABEL => protocol.to_owned(),
+        DIRECTION_LABEL => direction.as_str(),
+        RESULT_LABEL => result.as_str()
+    );
+}
@@ -4,7 +4,7 @@ use libp2p::swarm::dial_opts::DialOpts;
 use libp2p::swarm::{DialError, NetworkBehaviour, SwarmEvent};
 use libp2p::{Multiaddr, PeerId, Swarm};
 
-use crate::streamed_bytes::behaviour::{Behaviour, PeerNotConnected, SessionIdNotFoundError};
+use crate::streamed_bytes::behaviour::{Behaviour, SessionIdNotFoundError};
 use crate::streamed_bytes::{InboundSessionId, OutboundSessionId};
 use crate::{PeerAddressConfig, Protocol};
 
@@ -22,7 +22,7 @@ pub trait SwarmTrait: Stream<Item = Event> + Unpin {
         query: Vec<u8>,
         peer_id: PeerId,
         protocol: Protocol,
-    ) -> Result<OutboundSessionId, PeerNotConnected>;
+    ) -> OutboundSessionId;
 
     fn dial(&mut self, peer: PeerAddressConfig) -> Result<(), DialError>;
 }
@@ -41,7 +41,7 @@ impl SwarmTrait for Swarm<Behaviour> {
         query: Vec<u8>,
         peer_id: PeerId,
         protocol: Protocol,
-    ) -> Result<OutboundSessionId, PeerNotConnected> {
+    ) -> OutboundSessionId {
         self.behaviour_mut().send_query(query, peer_id, protocol.into())
     }
 
@@ -51,6 +51,8 @@ impl SwarmTrait for Swarm<Behaviour> {
             .expect("string to multiaddr failed")
             .with(LibP2pProtocol::Tcp(peer.tcp_port));
 
+        // Remember the address so that redials of the peer made by the behaviour will use it.
+        self.behaviour_mut().add_address(peer.peer_id, address.clone());
         self.dial(DialOpts::peer_id(peer.peer_id).addresses(vec![address]).build())
     }
 }
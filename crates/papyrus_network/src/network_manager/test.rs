@@ -1,41 +1,65 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 use std::vec;
 
 use deadqueue::unlimited::Queue;
 use futures::channel::mpsc::{unbounded, Sender, UnboundedSender};
-use futures::future::poll_fn;
+use futures::future::{poll_fn, BoxFuture};
 use futures::stream::{FuturesUnordered, Stream};
 use futures::{pin_mut, Future, FutureExt, SinkExt, StreamExt};
-use libp2p::PeerId;
+use libp2p::{PeerId, StreamProtocol};
+use prometheus_parse::Value::Counter;
 use prost::Message;
-use starknet_api::block::{BlockHeader, BlockNumber};
+use starknet_api::block::{BlockHash, BlockHeader, BlockNumber, BlockSignature};
+use starknet_api::crypto::Signature;
+use starknet_api::hash::StarkFelt;
+use test_utils::prometheus_is_contained;
 use tokio::select;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
+use super::query_metrics::{DIRECTION_LABEL, PROCESSED_QUERIES, PROTOCOL_LABEL, RESULT_LABEL};
 use super::swarm_trait::{Event, SwarmTrait};
-use super::GenericNetworkManager;
+use super::{DBExecutorCommand, GenericNetworkManager, ManagerStats, RecordedQuery};
 use crate::db_executor::{
     poll_query_execution_set,
     DBExecutor,
     DBExecutorError,
     Data,
+    DataTransformer,
     FetchBlockDataFromDb,
     QueryId,
+    QueryIdGenerator,
 };
 use crate::protobuf_messages::protobuf;
-use crate::streamed_bytes::behaviour::{PeerNotConnected, SessionIdNotFoundError};
+use crate::streamed_bytes::behaviour::{SessionError, SessionIdNotFoundError};
 use crate::streamed_bytes::{GenericEvent, InboundSessionId, OutboundSessionId};
-use crate::{BlockHashOrNumber, DataType, Direction, InternalQuery, PeerAddressConfig, Query};
+use crate::test_utils::PROMETHEUS_HANDLE;
+use crate::{
+    BlockHashOrNumber,
+    DataType,
+    Direction,
+    HeaderVerificationError,
+    HeaderVerifier,
+    InternalQuery,
+    PeerAddressConfig,
+    Query,
+    QueryFilter,
+    RejectionReason,
+    SessionEvent,
+};
 
 #[derive(Default)]
 struct MockSwarm {
-    pub pending_events: Queue<Event>,
+    pub pending_events: Arc<Queue<Event>>,
     pub sent_queries: Vec<(InternalQuery, PeerId)>,
     inbound_session_id_to_data_sender: HashMap<InboundSessionId, UnboundedSender<Data>>,
+    // Inbound sessions whose data is decoded as chain tip responses instead of block headers.
+    chain_tip_inbound_session_ids: HashSet<InboundSessionId>,
     next_outbound_session_id: usize,
 }
 
@@ -62,6 +86,14 @@ impl MockSwarm {
         data_receiver.collect()
     }
 
+    pub fn get_chain_tip_sent_to_inbound_session(
+        &mut self,
+        inbound_session_id: InboundSessionId,
+    ) -> impl Future<Output = Vec<Data>> {
+        self.chain_tip_inbound_session_ids.insert(inbound_session_id);
+        self.get_data_sent_to_inbound_session(inbound_session_id)
+    }
+
     fn create_received_data_events_for_query(
         &self,
         query: InternalQuery,
@@ -107,7 +139,11 @@ impl SwarmTrait for MockSwarm {
             .inbound_session_id_to_data_sender
             .get(&inbound_session_id)
             .expect("Called send_data without calling get_data_sent_to_inbound_session first");
-        let data = protobuf::BlockHeadersResponse::decode(&data[..]).unwrap().try_into().unwrap();
+        let data = if self.chain_tip_inbound_session_ids.contains(&inbound_session_id) {
+            protobuf::ChainTipResponse::decode(&data[..]).unwrap().try_into().unwrap()
+        } else {
+            protobuf::BlockHeadersResponse::decode(&data[..]).unwrap().try_into().unwrap()
+        };
         let is_fin = matches!(data, Data::Fin);
         data_sender.unbounded_send(data).unwrap();
         if is_fin {
@@ -121,7 +157,7 @@ impl SwarmTrait for MockSwarm {
         query: Vec<u8>,
         peer_id: PeerId,
         _protocol: crate::Protocol,
-    ) -> Result<OutboundSessionId, PeerNotConnected> {
+    ) -> OutboundSessionId {
         let query = protobuf::BlockHeadersRequest::decode(&query[..])
             .expect("failed to decode protobuf BlockHeadersRequest")
             .try_into()
@@ -130,7 +166,7 @@ impl SwarmTrait for MockSwarm {
         let outbound_session_id = OutboundSessionId { value: self.next_outbound_session_id };
         self.create_received_data_events_for_query(query, outbound_session_id);
         self.next_outbound_session_id += 1;
-        Ok(outbound_session_id)
+        outbound_session_id
     }
 
     fn dial(&mut self, _peer: PeerAddressConfig) -> Result<(), libp2p::swarm::DialError> {
@@ -140,9 +176,27 @@ impl SwarmTrait for MockSwarm {
 
 #[derive(Default)]
 struct MockDBExecutor {
-    next_query_id: usize,
+    query_id_generator: QueryIdGenerator,
     pub query_to_headers: HashMap<InternalQuery, Vec<BlockHeader>>,
+    pub chain_tip: Option<(BlockNumber, BlockHash)>,
+    data_transformer: Option<Arc<dyn DataTransformer>>,
     query_execution_set: FuturesUnordered<JoinHandle<Result<QueryId, DBExecutorError>>>,
+    is_paused: bool,
+    // Queries that were registered while paused. They're spawned on resume.
+    paused_query_executions: Vec<BoxFuture<'static, Result<QueryId, DBExecutorError>>>,
+}
+
+impl MockDBExecutor {
+    fn execute_query(
+        &mut self,
+        query_execution: impl Future<Output = Result<QueryId, DBExecutorError>> + Send + 'static,
+    ) {
+        if self.is_paused {
+            self.paused_query_executions.push(query_execution.boxed());
+        } else {
+            self.query_execution_set.push(tokio::task::spawn(query_execution));
+        }
+    }
 }
 
 impl Stream for MockDBExecutor {
@@ -158,34 +212,111 @@ impl DBExecutor for MockDBExecutor {
     fn register_query(
         &mut self,
         query: InternalQuery,
-        _data_type: impl FetchBlockDataFromDb + Send,
+        _data_type: Box<dyn FetchBlockDataFromDb + Send>,
         mut sender: Sender<Data>,
     ) -> QueryId {
-        let query_id = QueryId(self.next_query_id);
-        self.next_query_id += 1;
+        let query_id = self.query_id_generator.next_id();
         let headers = self.query_to_headers.get(&query).unwrap().clone();
-        self.query_execution_set.push(tokio::task::spawn(async move {
+        let data_transformer = self.data_transformer.clone();
+        self.execute_query(async move {
             {
                 for header in headers.iter().cloned() {
                     // Using poll_fn because Sender::poll_ready is not a future
-                    if let Ok(()) = poll_fn(|cx| sender.poll_ready(cx)).await {
-                        if let Err(e) = sender.start_send(Data::BlockHeaderAndSignature {
-                            header,
-                            signatures: vec![],
-                        }) {
-                            return Err(DBExecutorError::SendError { query_id, send_error: e });
-                        };
+                    if poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+                        return Err(DBExecutorError::ChannelClosed { query_id });
+                    }
+                    let block_number = header.block_number;
+                    let mut data = Data::BlockHeaderAndSignature { header, signatures: vec![] };
+                    if let Some(data_transformer) = data_transformer.as_ref() {
+                        data = data_transformer.transform(data, block_number, query_id)?;
                     }
+                    if let Err(e) = sender.start_send(data) {
+                        return Err(DBExecutorError::SendError { query_id, send_error: e });
+                    };
                 }
                 Ok(query_id)
             }
-        }));
+        });
+        query_id
+    }
+
+    fn register_chain_tip_query(&mut self, mut sender: Sender<Data>) -> QueryId {
+        let query_id = self.query_id_generator.next_id();
+        let chain_tip = self.chain_tip;
+        self.execute_query(async move {
+            if let Some((block_number, block_hash)) = chain_tip {
+                if let Err(e) = sender.send(Data::ChainTip { block_number, block_hash }).await {
+                    return Err(DBExecutorError::SendError { query_id, send_error: e });
+                }
+            }
+            Ok(query_id)
+        });
         query_id
     }
+
+    fn pause(&mut self) {
+        self.is_paused = true;
+    }
+
+    fn resume(&mut self) {
+        self.is_paused = false;
+        for query_execution in self.paused_query_executions.drain(..) {
+            self.query_execution_set.push(tokio::task::spawn(query_execution));
+        }
+    }
+
+    fn set_data_transformer(&mut self, data_transformer: Arc<dyn DataTransformer>) {
+        self.data_transformer = Some(data_transformer);
+    }
 }
 
 const HEADER_BUFFER_SIZE: usize = 100;
 
+// Accepts only the default signature.
+struct MockHeaderVerifier;
+
+impl HeaderVerifier for MockHeaderVerifier {
+    fn verify_signature(&self, _block_hash: &BlockHash, signature: &BlockSignature) -> bool {
+        *signature == BlockSignature::default()
+    }
+}
+
+struct RejectBackwardQueries;
+
+impl QueryFilter for RejectBackwardQueries {
+    fn should_serve(&self, query: &InternalQuery) -> bool {
+        query.direction == Direction::Forward
+    }
+}
+
+fn encode_signed_header(block_number: BlockNumber, signatures: Vec<BlockSignature>) -> Vec<u8> {
+    let mut data_bytes = vec![];
+    protobuf::BlockHeadersResponse::try_from(Data::BlockHeaderAndSignature {
+        header: BlockHeader { block_number, ..Default::default() },
+        signatures,
+    })
+    .unwrap()
+    .encode(&mut data_bytes)
+    .unwrap();
+    data_bytes
+}
+
+// The queries are written by another thread, so wait until the given number of them reach the file.
+async fn wait_for_recorded_queries(path: &Path, num_queries: usize) -> Vec<RecordedQuery> {
+    let recording = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let recording = std::fs::read_to_string(path).unwrap_or_default();
+            if recording.ends_with('\n') && recording.lines().count() == num_queries {
+                return recording;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Timed out waiting for the queries to be recorded");
+    recording.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+}
+
 #[tokio::test]
 async fn register_subscriber_and_use_channels() {
     // create mocked network manager
@@ -230,6 +361,252 @@ async fn register_subscriber_and_use_channels() {
     }
 }
 
+#[tokio::test]
+async fn disconnect_during_outbound_session_is_reported_to_subscriber() {
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        Some(PeerAddressConfig { peer_id: PeerId::random(), ..Default::default() }),
+    );
+    let (_query_sender, mut response_receivers) =
+        network_manager.register_subscriber(vec![crate::Protocol::SignedBlockHeader]);
+
+    // Simulate the connection closing before the session received Fin.
+    let outbound_session_id = OutboundSessionId { value: 0 };
+    network_manager.swarm.pending_events.push(Event::Behaviour(GenericEvent::SessionFailed {
+        session_id: outbound_session_id.into(),
+        error: SessionError::ConnectionClosed,
+    }));
+
+    tokio::select! {
+        _ = network_manager.run() => panic!("network manager ended"),
+        session_event = response_receivers.session_events_receiver.next() => {
+            assert_eq!(
+                session_event.unwrap(),
+                SessionEvent::SessionClosedByDisconnect { outbound_session_id }
+            );
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[test]
+fn resume_session_after_disconnect_sends_remaining_blocks() {
+    let peer_id = PeerId::random();
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    let query = || Query {
+        start_block: BlockNumber(0),
+        direction: Direction::Forward,
+        limit: 5,
+        step: 1,
+        data_type: DataType::SignedBlockHeader,
+    };
+
+    // Blocks 0 to 2 were received before the session was closed by a disconnect.
+    network_manager.handle_behaviour_event(GenericEvent::SessionFailed {
+        session_id: OutboundSessionId { value: 0 }.into(),
+        error: SessionError::ConnectionClosed,
+    });
+    network_manager.resume_session(query(), BlockNumber(2), peer_id);
+    assert_eq!(
+        network_manager.swarm.sent_queries,
+        vec![(
+            InternalQuery {
+                start_block: BlockHashOrNumber::Number(BlockNumber(3)),
+                direction: Direction::Forward,
+                limit: 2,
+                step: 1,
+            },
+            peer_id
+        )]
+    );
+
+    // Nothing is left to resume after the last block of the query.
+    network_manager.resume_session(query(), BlockNumber(4), peer_id);
+    assert_eq!(network_manager.swarm.sent_queries.len(), 1);
+}
+
+#[tokio::test]
+async fn received_headers_are_verified() {
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    let (_query_sender, mut response_receivers) =
+        network_manager.register_subscriber(vec![crate::Protocol::SignedBlockHeader]);
+    network_manager.set_header_verifier(Box::new(MockHeaderVerifier));
+
+    let outbound_session_id = OutboundSessionId { value: 0 };
+    let invalid_signature =
+        BlockSignature(Signature { r: StarkFelt::from(1u8), s: StarkFelt::from(1u8) });
+    for (block_number, signatures) in [
+        (BlockNumber(0), vec![BlockSignature::default()]),
+        (BlockNumber(1), vec![invalid_signature]),
+        (BlockNumber(2), vec![]),
+    ] {
+        network_manager.handle_behaviour_event(GenericEvent::ReceivedData {
+            outbound_session_id,
+            data: encode_signed_header(block_number, signatures),
+        });
+    }
+
+    let signed_header = response_receivers.signed_headers_receiver.next().await.unwrap().unwrap();
+    assert_eq!(signed_header.block_header.block_number, BlockNumber(0));
+    assert!(response_receivers.signed_headers_receiver.next().now_or_never().is_none());
+
+    assert_eq!(
+        response_receivers.session_events_receiver.next().await.unwrap(),
+        SessionEvent::HeaderRejected {
+            outbound_session_id,
+            block_number: Some(BlockNumber(1)),
+            error: HeaderVerificationError::InvalidSignature,
+        }
+    );
+    assert_eq!(
+        response_receivers.session_events_receiver.next().await.unwrap(),
+        SessionEvent::HeaderRejected {
+            outbound_session_id,
+            block_number: Some(BlockNumber(2)),
+            error: HeaderVerificationError::MissingSignature,
+        }
+    );
+}
+
+#[tokio::test]
+async fn malformed_received_header_is_rejected() {
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    let (_query_sender, mut response_receivers) =
+        network_manager.register_subscriber(vec![crate::Protocol::SignedBlockHeader]);
+    network_manager.set_header_verifier(Box::new(MockHeaderVerifier));
+
+    let outbound_session_id = OutboundSessionId { value: 0 };
+    network_manager.handle_behaviour_event(GenericEvent::ReceivedData {
+        outbound_session_id,
+        data: vec![0xff; 10],
+    });
+
+    assert!(response_receivers.signed_headers_receiver.next().now_or_never().is_none());
+    assert_eq!(
+        response_receivers.session_events_receiver.next().await.unwrap(),
+        SessionEvent::HeaderRejected {
+            outbound_session_id,
+            block_number: None,
+            error: HeaderVerificationError::Malformed,
+        }
+    );
+}
+
+#[tokio::test]
+async fn malformed_received_header_is_dropped_without_verifier() {
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    let (_query_sender, mut response_receivers) =
+        network_manager.register_subscriber(vec![crate::Protocol::SignedBlockHeader]);
+
+    network_manager.handle_behaviour_event(GenericEvent::ReceivedData {
+        outbound_session_id: OutboundSessionId { value: 0 },
+        data: vec![0xff; 10],
+    });
+
+    assert!(response_receivers.signed_headers_receiver.next().now_or_never().is_none());
+    assert!(response_receivers.session_events_receiver.next().now_or_never().is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_subscriber_stalls_received_data_without_dropping_it() {
+    let buffer_size = 2;
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        buffer_size,
+        None,
+    );
+    let (_query_sender, mut response_receivers) =
+        network_manager.register_subscriber(vec![crate::Protocol::SignedBlockHeader]);
+
+    let num_headers = 10;
+    let outbound_session_id = OutboundSessionId { value: 0 };
+    for block_number in 0..num_headers {
+        network_manager.swarm.pending_events.push(Event::Behaviour(GenericEvent::ReceivedData {
+            outbound_session_id,
+            data: encode_signed_header(BlockNumber(block_number), vec![]),
+        }));
+    }
+    let pending_swarm_events = network_manager.swarm.pending_events.clone();
+    let network_manager_run = network_manager.run();
+    pin_mut!(network_manager_run);
+
+    // The subscriber doesn't read, so the network manager stops reading from the swarm once the
+    // subscriber's buffer is full.
+    select! {
+        _ = &mut network_manager_run => panic!("network manager ended"),
+        _ = sleep(Duration::from_secs(5)) => {}
+    }
+    assert!(!pending_swarm_events.is_empty());
+
+    let signed_headers = select! {
+        _ = &mut network_manager_run => panic!("network manager ended"),
+        signed_headers = response_receivers
+            .signed_headers_receiver
+            .by_ref()
+            .take(num_headers.try_into().unwrap())
+            .collect::<Vec<_>>() => signed_headers,
+    };
+    let block_numbers = signed_headers
+        .into_iter()
+        .map(|signed_header| signed_header.unwrap().block_header.block_number)
+        .collect::<Vec<_>>();
+    assert_eq!(block_numbers, (0..num_headers).map(BlockNumber).collect::<Vec<_>>());
+    assert!(pending_swarm_events.is_empty());
+}
+
+#[tokio::test]
+async fn query_rejected_by_peer_is_reported_to_subscriber() {
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    let (_query_sender, mut response_receivers) =
+        network_manager.register_subscriber(vec![crate::Protocol::SignedBlockHeader]);
+
+    let outbound_session_id = OutboundSessionId { value: 0 };
+    let rejection = protobuf::BlockHeadersResponse::try_from(Data::Rejection {
+        reason: RejectionReason::Filtered,
+    })
+    .unwrap();
+    network_manager.handle_behaviour_event(GenericEvent::ReceivedData {
+        outbound_session_id,
+        data: rejection.encode_to_vec(),
+    });
+
+    assert!(response_receivers.signed_headers_receiver.next().now_or_never().is_none());
+    assert_eq!(
+        response_receivers.session_events_receiver.next().await.unwrap(),
+        SessionEvent::QueryRejected { outbound_session_id, reason: RejectionReason::Filtered }
+    );
+}
+
 #[tokio::test]
 async fn process_incoming_query() {
     // Create data for test.
@@ -295,3 +672,793 @@ async fn process_incoming_query() {
         }
     }
 }
+
+#[tokio::test]
+async fn data_transformer_is_applied_to_inbound_query_data() {
+    const BLOCK_NUM: u64 = 0;
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(BLOCK_NUM)),
+        direction: Direction::Forward,
+        limit: 3,
+        step: 1,
+    };
+    let headers = (0..3)
+        .map(|i| BlockHeader { block_number: BlockNumber(i), ..Default::default() })
+        .collect::<Vec<_>>();
+
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, headers.clone());
+
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    let mut query_bytes = vec![];
+    protobuf::BlockHeadersRequest::from(query).encode(&mut query_bytes).unwrap();
+    mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+        query: query_bytes,
+        inbound_session_id,
+        peer_id: PeerId::random(),
+        protocol_name: crate::Protocol::SignedBlockHeader.into(),
+    }));
+    let get_data_fut = mock_swarm.get_data_sent_to_inbound_session(inbound_session_id);
+
+    // Tags each header by adding a signature made of its block number.
+    struct TagTransformer;
+    impl DataTransformer for TagTransformer {
+        fn transform(
+            &self,
+            data: Data,
+            block_number: BlockNumber,
+            _query_id: QueryId,
+        ) -> Result<Data, DBExecutorError> {
+            let Data::BlockHeaderAndSignature { header, mut signatures } = data else {
+                panic!("Unexpected data type");
+            };
+            signatures.push(tag(block_number));
+            Ok(Data::BlockHeaderAndSignature { header, signatures })
+        }
+    }
+    fn tag(block_number: BlockNumber) -> BlockSignature {
+        BlockSignature(Signature { r: block_number.0.into(), s: block_number.0.into() })
+    }
+
+    let mut network_manager =
+        GenericNetworkManager::generic_new(mock_swarm, mock_db_executor, HEADER_BUFFER_SIZE, None);
+    network_manager.set_data_transformer(Arc::new(TagTransformer));
+
+    select! {
+        inbound_session_data = get_data_fut => {
+            let mut expected_data = headers
+                .into_iter()
+                .map(|header| {
+                    let signatures = vec![tag(header.block_number)];
+                    Data::BlockHeaderAndSignature { header, signatures }
+                })
+                .collect::<Vec<_>>();
+            expected_data.push(Data::Fin);
+            assert_eq!(inbound_session_data, expected_data);
+        }
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the session finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+async fn process_incoming_query_without_data_sends_only_fin() {
+    const BLOCK_NUM: u64 = 1000;
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(BLOCK_NUM)),
+        direction: Direction::Forward,
+        limit: 5,
+        step: 1,
+    };
+
+    // None of the queried blocks are in the storage, so the DB executor doesn't produce any data.
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, vec![]);
+
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    let mut query_bytes = vec![];
+    protobuf::BlockHeadersRequest {
+        iteration: Some(protobuf::Iteration {
+            start: Some(protobuf::iteration::Start::BlockNumber(BLOCK_NUM)),
+            direction: protobuf::iteration::Direction::Forward as i32,
+            limit: query.limit,
+            step: query.step,
+        }),
+    }
+    .encode(&mut query_bytes)
+    .unwrap();
+    mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+        query: query_bytes,
+        inbound_session_id,
+        peer_id: PeerId::random(),
+        protocol_name: crate::Protocol::SignedBlockHeader.into(),
+    }));
+
+    let get_data_fut = mock_swarm.get_data_sent_to_inbound_session(inbound_session_id);
+
+    let network_manager =
+        GenericNetworkManager::generic_new(mock_swarm, mock_db_executor, HEADER_BUFFER_SIZE, None);
+
+    select! {
+        inbound_session_data = get_data_fut => {
+            assert_eq!(inbound_session_data, vec![Data::Fin]);
+        }
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the session finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+async fn rejected_inbound_query_gets_rejection_and_fin() {
+    const BLOCK_NUM: u64 = 10;
+
+    // The DB executor doesn't know any query, so it would panic if the query reached it.
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    let mut query_bytes = vec![];
+    protobuf::BlockHeadersRequest {
+        iteration: Some(protobuf::Iteration {
+            start: Some(protobuf::iteration::Start::BlockNumber(BLOCK_NUM)),
+            direction: protobuf::iteration::Direction::Backward as i32,
+            limit: 5,
+            step: 1,
+        }),
+    }
+    .encode(&mut query_bytes)
+    .unwrap();
+    mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+        query: query_bytes,
+        inbound_session_id,
+        peer_id: PeerId::random(),
+        protocol_name: crate::Protocol::SignedBlockHeader.into(),
+    }));
+
+    let get_data_fut = mock_swarm.get_data_sent_to_inbound_session(inbound_session_id);
+
+    let mut network_manager = GenericNetworkManager::generic_new(
+        mock_swarm,
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    network_manager.set_query_filter(Box::new(RejectBackwardQueries));
+
+    select! {
+        inbound_session_data = get_data_fut => {
+            assert_eq!(
+                inbound_session_data,
+                vec![Data::Rejection { reason: RejectionReason::Filtered }, Data::Fin]
+            );
+        }
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the session finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+async fn inbound_sessions_beyond_max_are_rejected() {
+    let handle = &*PROMETHEUS_HANDLE;
+
+    // Using a protocol that no other test uses, since the metrics recorder is global.
+    const PROTOCOL: &str = "/max_inbound_sessions_test/1";
+    const MAX_INBOUND_SESSIONS: usize = 2;
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 1,
+        step: 1,
+    };
+    let header = BlockHeader::default();
+
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, vec![header.clone()]);
+    let mut mock_swarm = MockSwarm::default();
+    let mut query_bytes = vec![];
+    protobuf::BlockHeadersRequest::from(query).encode(&mut query_bytes).unwrap();
+    let new_inbound_session = |value| {
+        Event::Behaviour(GenericEvent::NewInboundSession {
+            query: query_bytes.clone(),
+            inbound_session_id: InboundSessionId { value },
+            peer_id: PeerId::random(),
+            protocol_name: StreamProtocol::new(PROTOCOL),
+        })
+    };
+    // Open the maximal number of sessions and then one more, which should be rejected. Once one of
+    // the sessions completes, a new session should be accepted.
+    for value in 0..=MAX_INBOUND_SESSIONS {
+        mock_swarm.pending_events.push(new_inbound_session(value));
+    }
+    mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::SessionFinishedSuccessfully {
+        session_id: InboundSessionId { value: 0 }.into(),
+    }));
+    mock_swarm.pending_events.push(new_inbound_session(MAX_INBOUND_SESSIONS + 1));
+    let get_data_fut = futures::future::join_all(
+        (0..=MAX_INBOUND_SESSIONS + 1)
+            .map(|value| mock_swarm.get_data_sent_to_inbound_session(InboundSessionId { value }))
+            .collect::<Vec<_>>(),
+    );
+
+    let mut network_manager = GenericNetworkManager::generic_new(
+        mock_swarm,
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    network_manager.register_db_executor(
+        StreamProtocol::new(PROTOCOL),
+        mock_db_executor,
+        DataType::SignedBlockHeader,
+    );
+    network_manager.set_max_inbound_sessions(MAX_INBOUND_SESSIONS);
+
+    let accepted_session_data =
+        vec![Data::BlockHeaderAndSignature { header, signatures: vec![] }, Data::Fin];
+    select! {
+        inbound_sessions_data = get_data_fut => {
+            assert_eq!(
+                inbound_sessions_data,
+                vec![
+                    accepted_session_data.clone(),
+                    accepted_session_data.clone(),
+                    vec![Data::Rejection { reason: RejectionReason::TooManySessions }, Data::Fin],
+                    accepted_session_data,
+                ]
+            );
+        }
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the sessions finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+
+    let labels =
+        [(PROTOCOL_LABEL, PROTOCOL), (DIRECTION_LABEL, "inbound"), (RESULT_LABEL, "rejected")];
+    assert_eq!(
+        prometheus_is_contained(handle.render(), PROCESSED_QUERIES, &labels),
+        Some(Counter(1f64))
+    );
+}
+
+#[tokio::test]
+async fn record_inbound_queries() {
+    let queries = [
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+            direction: Direction::Forward,
+            limit: 2,
+            step: 1,
+        },
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(10)),
+            direction: Direction::Forward,
+            limit: 3,
+            step: 2,
+        },
+    ];
+    let peer_ids = [PeerId::random(), PeerId::random()];
+
+    let mut mock_db_executor = MockDBExecutor::default();
+    let mut mock_swarm = MockSwarm::default();
+    let mut get_data_futs = vec![];
+    for (value, (query, peer_id)) in queries.iter().zip(peer_ids).enumerate() {
+        mock_db_executor.query_to_headers.insert(*query, vec![]);
+        let inbound_session_id = InboundSessionId { value };
+        let mut query_bytes = vec![];
+        protobuf::BlockHeadersRequest {
+            iteration: Some(protobuf::Iteration {
+                start: Some(protobuf::iteration::Start::BlockNumber(match query.start_block {
+                    BlockHashOrNumber::Number(BlockNumber(block_number)) => block_number,
+                    BlockHashOrNumber::Hash(_) => unreachable!(),
+                })),
+                direction: protobuf::iteration::Direction::Forward as i32,
+                limit: query.limit,
+                step: query.step,
+            }),
+        }
+        .encode(&mut query_bytes)
+        .unwrap();
+        mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+            query: query_bytes,
+            inbound_session_id,
+            peer_id,
+            protocol_name: crate::Protocol::SignedBlockHeader.into(),
+        }));
+        get_data_futs.push(mock_swarm.get_data_sent_to_inbound_session(inbound_session_id));
+    }
+
+    let recording_dir = tempfile::tempdir().unwrap();
+    let recording_path = recording_dir.path().join("queries.jsonl");
+    let mut network_manager =
+        GenericNetworkManager::generic_new(mock_swarm, mock_db_executor, HEADER_BUFFER_SIZE, None);
+    network_manager.record_queries_to(&recording_path).unwrap();
+
+    select! {
+        _ = futures::future::join_all(get_data_futs) => {}
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the sessions finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+
+    let recorded_queries = wait_for_recorded_queries(&recording_path, queries.len())
+        .await
+        .into_iter()
+        .map(|RecordedQuery { peer_id, query, .. }| (peer_id, query))
+        .collect::<Vec<_>>();
+    assert_eq!(recorded_queries, peer_ids.into_iter().zip(queries).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn replay_recorded_queries() {
+    let queries = [
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+            direction: Direction::Forward,
+            limit: 2,
+            step: 1,
+        },
+        InternalQuery {
+            start_block: BlockHashOrNumber::Number(BlockNumber(10)),
+            direction: Direction::Backward,
+            limit: 3,
+            step: 2,
+        },
+    ];
+    let recording_dir = tempfile::tempdir().unwrap();
+    let recording_path = recording_dir.path().join("queries.jsonl");
+    let mut recording_network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    recording_network_manager.record_queries_to(&recording_path).unwrap();
+    for query in queries {
+        recording_network_manager.record_inbound_query(PeerId::random(), query);
+    }
+    wait_for_recorded_queries(&recording_path, queries.len()).await;
+
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    let target_peer = PeerId::random();
+    network_manager.replay_queries(&recording_path, target_peer, false).unwrap();
+
+    // Drive the network manager manually since run consumes it.
+    while let Some((peer_id, query)) = network_manager.replayed_queries.next().await {
+        network_manager.send_replayed_query(peer_id, query);
+    }
+
+    assert_eq!(
+        network_manager.swarm.sent_queries,
+        queries.into_iter().map(|query| (query, target_peer)).collect::<Vec<_>>()
+    );
+    assert_eq!(network_manager.stats().active_outbound_sessions, queries.len());
+}
+
+#[tokio::test]
+async fn stats_count_processed_queries_and_sent_bytes() {
+    const BLOCK_NUM: u64 = 0;
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(BLOCK_NUM)),
+        direction: Direction::Forward,
+        limit: 5,
+        step: 1,
+    };
+    let headers = (0..5)
+        .map(|i| BlockHeader { block_number: BlockNumber(i), ..Default::default() })
+        .collect::<Vec<_>>();
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, headers);
+
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    let get_data_fut = mock_swarm.get_data_sent_to_inbound_session(inbound_session_id);
+    let mut network_manager =
+        GenericNetworkManager::generic_new(mock_swarm, mock_db_executor, HEADER_BUFFER_SIZE, None);
+    assert_eq!(network_manager.stats(), ManagerStats::default());
+
+    let mut query_bytes = vec![];
+    protobuf::BlockHeadersRequest {
+        iteration: Some(protobuf::Iteration {
+            start: Some(protobuf::iteration::Start::BlockNumber(BLOCK_NUM)),
+            direction: protobuf::iteration::Direction::Forward as i32,
+            limit: query.limit,
+            step: query.step,
+        }),
+    }
+    .encode(&mut query_bytes)
+    .unwrap();
+    network_manager.handle_behaviour_event(GenericEvent::NewInboundSession {
+        query: query_bytes,
+        inbound_session_id,
+        peer_id: PeerId::random(),
+        protocol_name: crate::Protocol::SignedBlockHeader.into(),
+    });
+
+    // Drive the network manager manually since run consumes it.
+    let (protocol, res) = network_manager.db_executors.next().await.unwrap();
+    network_manager.handle_db_executor_result(protocol, res);
+    while let Some(res) = network_manager.query_results_router.next().await {
+        network_manager.handle_query_result_routing_to_other_peer(res);
+    }
+    get_data_fut.await;
+
+    let stats = network_manager.stats();
+    assert_eq!(stats.active_inbound_sessions, 1);
+    assert_eq!(stats.queries_processed, 1);
+    assert_eq!(stats.failed_queries, 0);
+    assert!(stats.bytes_sent > 0);
+}
+
+#[tokio::test]
+async fn processed_inbound_query_is_counted_in_metrics() {
+    let handle = &*PROMETHEUS_HANDLE;
+
+    // Using a protocol that no other test uses, since the metrics recorder is global.
+    const PROTOCOL: &str = "/metrics_test/1";
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 5,
+        step: 1,
+    };
+    let headers = (0..5)
+        .map(|i| BlockHeader { block_number: BlockNumber(i), ..Default::default() })
+        .collect::<Vec<_>>();
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, headers);
+
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    network_manager.register_db_executor(
+        StreamProtocol::new(PROTOCOL),
+        mock_db_executor,
+        DataType::SignedBlockHeader,
+    );
+
+    let mut query_bytes = vec![];
+    protobuf::BlockHeadersRequest {
+        iteration: Some(protobuf::Iteration {
+            start: Some(protobuf::iteration::Start::BlockNumber(0)),
+            direction: protobuf::iteration::Direction::Forward as i32,
+            limit: query.limit,
+            step: query.step,
+        }),
+    }
+    .encode(&mut query_bytes)
+    .unwrap();
+    network_manager.handle_behaviour_event(GenericEvent::NewInboundSession {
+        query: query_bytes,
+        inbound_session_id: InboundSessionId { value: 0 },
+        peer_id: PeerId::random(),
+        protocol_name: StreamProtocol::new(PROTOCOL),
+    });
+
+    // Drive the network manager manually since run consumes it.
+    let (protocol, res) = network_manager.db_executors.next().await.unwrap();
+    network_manager.handle_db_executor_result(protocol, res);
+
+    let labels = [(PROTOCOL_LABEL, PROTOCOL), (DIRECTION_LABEL, "inbound"), (RESULT_LABEL, "ok")];
+    assert_eq!(
+        prometheus_is_contained(handle.render(), PROCESSED_QUERIES, &labels),
+        Some(Counter(1f64))
+    );
+}
+
+#[tokio::test]
+async fn processed_outbound_query_is_counted_by_its_protocol() {
+    let handle = &*PROMETHEUS_HANDLE;
+    let labels = [
+        (PROTOCOL_LABEL, crate::Protocol::SignedBlockHeader.as_str()),
+        (DIRECTION_LABEL, "outbound"),
+        (RESULT_LABEL, "ok"),
+    ];
+    let processed_queries =
+        || match prometheus_is_contained(handle.render(), PROCESSED_QUERIES, &labels) {
+            Some(Counter(count)) => count,
+            _ => 0f64,
+        };
+
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 1,
+        step: 1,
+    };
+    network_manager.send_replayed_query(PeerId::random(), query);
+    let session_id = OutboundSessionId { value: 0 }.into();
+
+    let processed_queries_before = processed_queries();
+    network_manager
+        .handle_behaviour_event(GenericEvent::SessionFinishedSuccessfully { session_id });
+    assert_eq!(processed_queries(), processed_queries_before + 1f64);
+
+    // The session's protocol is forgotten once it finishes, so it isn't counted again.
+    network_manager
+        .handle_behaviour_event(GenericEvent::SessionFinishedSuccessfully { session_id });
+    assert_eq!(processed_queries(), processed_queries_before + 1f64);
+}
+
+#[tokio::test]
+async fn route_inbound_queries_by_protocol() {
+    // Create data for test.
+    const BLOCK_NUM: u64 = 0;
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(BLOCK_NUM)),
+        direction: Direction::Forward,
+        limit: 5,
+        step: 1,
+    };
+    let headers = (0..5)
+        .map(|i| BlockHeader { block_number: BlockNumber(i), ..Default::default() })
+        .collect::<Vec<_>>();
+    let other_protocol_headers = (10..15)
+        .map(|i| BlockHeader { block_number: BlockNumber(i), ..Default::default() })
+        .collect::<Vec<_>>();
+    let other_protocol = StreamProtocol::new("/starknet/other_protocol/1");
+
+    // Setup two mock DB executors that reply to the same query with different headers, so we can
+    // tell which of them handled each query.
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, headers.clone());
+    let mut other_mock_db_executor = MockDBExecutor::default();
+    other_mock_db_executor.query_to_headers.insert(query, other_protocol_headers.clone());
+
+    // Setup mock swarm and tell it to return an event of new inbound query for each protocol.
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    let other_inbound_session_id = InboundSessionId { value: 1 };
+    let mut query_bytes = vec![];
+    protobuf::BlockHeadersRequest {
+        iteration: Some(protobuf::Iteration {
+            start: Some(protobuf::iteration::Start::BlockNumber(BLOCK_NUM)),
+            direction: protobuf::iteration::Direction::Forward as i32,
+            limit: query.limit,
+            step: query.step,
+        }),
+    }
+    .encode(&mut query_bytes)
+    .unwrap();
+    for (inbound_session_id, protocol_name) in [
+        (inbound_session_id, crate::Protocol::SignedBlockHeader.into()),
+        (other_inbound_session_id, other_protocol.clone()),
+    ] {
+        mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+            query: query_bytes.clone(),
+            inbound_session_id,
+            peer_id: PeerId::random(),
+            protocol_name,
+        }));
+    }
+
+    // Create futures that will return when Fin is sent with the data sent on the swarm.
+    let get_data_fut = mock_swarm.get_data_sent_to_inbound_session(inbound_session_id);
+    let get_other_data_fut = mock_swarm.get_data_sent_to_inbound_session(other_inbound_session_id);
+
+    let mut network_manager =
+        GenericNetworkManager::generic_new(mock_swarm, mock_db_executor, HEADER_BUFFER_SIZE, None);
+    network_manager.register_db_executor(
+        other_protocol,
+        other_mock_db_executor,
+        DataType::SignedBlockHeader,
+    );
+
+    let headers_to_expected_data = |headers: Vec<BlockHeader>| {
+        let mut expected_data = headers
+            .into_iter()
+            .map(|header| Data::BlockHeaderAndSignature { header, signatures: vec![] })
+            .collect::<Vec<_>>();
+        expected_data.push(Data::Fin);
+        expected_data
+    };
+
+    select! {
+        (inbound_session_data, other_inbound_session_data) =
+            futures::future::join(get_data_fut, get_other_data_fut) => {
+            assert_eq!(inbound_session_data, headers_to_expected_data(headers));
+            assert_eq!(other_inbound_session_data, headers_to_expected_data(other_protocol_headers));
+        }
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the session finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+async fn malformed_inbound_query_is_answered_with_fin() {
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+        query: vec![0xff; 10],
+        inbound_session_id,
+        peer_id: PeerId::random(),
+        protocol_name: crate::Protocol::SignedBlockHeader.into(),
+    }));
+    let get_data_fut = mock_swarm.get_data_sent_to_inbound_session(inbound_session_id);
+
+    let network_manager = GenericNetworkManager::generic_new(
+        mock_swarm,
+        MockDBExecutor::default(),
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+
+    select! {
+        inbound_session_data = get_data_fut => {
+            assert_eq!(inbound_session_data, vec![Data::Fin]);
+        }
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the session finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test]
+async fn db_executors_are_polled_in_turns() {
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 0,
+        step: 1,
+    };
+    let other_protocol = StreamProtocol::new("/starknet/other_protocol/1");
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, vec![]);
+    let mut other_mock_db_executor = MockDBExecutor::default();
+    other_mock_db_executor.query_to_headers.insert(query, vec![]);
+
+    let mut network_manager = GenericNetworkManager::generic_new(
+        MockSwarm::default(),
+        mock_db_executor,
+        HEADER_BUFFER_SIZE,
+        None,
+    );
+    network_manager.register_db_executor(
+        other_protocol.clone(),
+        other_mock_db_executor,
+        DataType::SignedBlockHeader,
+    );
+
+    // The first db executor has two finished queries and the other has one.
+    let protocol: StreamProtocol = crate::Protocol::SignedBlockHeader.into();
+    for query_protocol in [&protocol, &protocol, &other_protocol] {
+        let (sender, _receiver) = futures::channel::mpsc::channel(HEADER_BUFFER_SIZE);
+        network_manager.db_executors.register_query(query_protocol, query, sender).unwrap();
+    }
+    // Let the spawned queries finish before polling the db executors.
+    tokio::task::yield_now().await;
+
+    let mut polled_protocols = vec![];
+    for _ in 0..3 {
+        let (polled_protocol, res) = network_manager.db_executors.next().await.unwrap();
+        res.unwrap();
+        polled_protocols.push(polled_protocol);
+    }
+    assert_eq!(polled_protocols, vec![protocol.clone(), other_protocol, protocol]);
+}
+
+#[tokio::test]
+async fn chain_tip_query_is_answered_with_chain_tip_and_fin() {
+    let block_number = BlockNumber(7);
+    let block_hash = BlockHash(StarkFelt::from(7u8));
+    let mock_db_executor =
+        MockDBExecutor { chain_tip: Some((block_number, block_hash)), ..Default::default() };
+
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+        query: protobuf::ChainTipRequest {}.encode_to_vec(),
+        inbound_session_id,
+        peer_id: PeerId::random(),
+        protocol_name: crate::Protocol::ChainTip.into(),
+    }));
+    let get_data_fut = mock_swarm.get_chain_tip_sent_to_inbound_session(inbound_session_id);
+
+    let network_manager =
+        GenericNetworkManager::generic_new(mock_swarm, mock_db_executor, HEADER_BUFFER_SIZE, None);
+
+    select! {
+        inbound_session_data = get_data_fut => {
+            assert_eq!(
+                inbound_session_data,
+                vec![Data::ChainTip { block_number, block_hash }, Data::Fin]
+            );
+        }
+        _ = network_manager.run() => {
+            panic!("GenericNetworkManager::run finished before the session finished");
+        }
+        _ = sleep(Duration::from_secs(5)) => {
+            panic!("Test timed out");
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn paused_db_executors_start_inbound_queries_on_resume() {
+    let query = InternalQuery {
+        start_block: BlockHashOrNumber::Number(BlockNumber(0)),
+        direction: Direction::Forward,
+        limit: 5,
+        step: 1,
+    };
+    let headers = (0..5)
+        .map(|i| BlockHeader { block_number: BlockNumber(i), ..Default::default() })
+        .collect::<Vec<_>>();
+    let mut mock_db_executor = MockDBExecutor::default();
+    mock_db_executor.query_to_headers.insert(query, headers.clone());
+
+    let mut mock_swarm = MockSwarm::default();
+    let inbound_session_id = InboundSessionId { value: 0 };
+    mock_swarm.pending_events.push(Event::Behaviour(GenericEvent::NewInboundSession {
+        query: protobuf::BlockHeadersRequest::from(query).encode_to_vec(),
+        inbound_session_id,
+        peer_id: PeerId::random(),
+        protocol_name: crate::Protocol::SignedBlockHeader.into(),
+    }));
+    let get_data_fut = mock_swarm.get_data_sent_to_inbound_session(inbound_session_id);
+    pin_mut!(get_data_fut);
+
+    let mut network_manager =
+        GenericNetworkManager::generic_new(mock_swarm, mock_db_executor, HEADER_BUFFER_SIZE, None);
+    network_manager.pause_db_executors();
+    let mut db_executor_controller = network_manager.register_db_executor_controller();
+    let run_fut = network_manager.run();
+    pin_mut!(run_fut);
+
+    // The query doesn't start while the db executors are paused.
+    select! {
+        _ = &mut get_data_fut => panic!("The query was served while the db executors were paused"),
+        _ = &mut run_fut => panic!("GenericNetworkManager::run finished unexpectedly"),
+        _ = sleep(Duration::from_secs(1)) => {}
+    }
+
+    db_executor_controller.send(DBExecutorCommand::Resume).await.unwrap();
+    let mut expected_data = headers
+        .into_iter()
+        .map(|header| Data::BlockHeaderAndSignature { header, signatures: vec![] })
+        .collect::<Vec<_>>();
+    expected_data.push(Data::Fin);
+    select! {
+        inbound_session_data = get_data_fut => assert_eq!(inbound_session_data, expected_data),
+        _ = run_fut => panic!("GenericNetworkManager::run finished before the session finished"),
+        _ = sleep(Duration::from_secs(5)) => panic!("Test timed out"),
+    }
+}
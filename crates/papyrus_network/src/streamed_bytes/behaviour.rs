@@ -10,8 +10,12 @@ use std::task::{Context, Poll, Waker};
 use std::time::Duration;
 
 use defaultmap::DefaultHashMap;
-use libp2p::core::Endpoint;
-use libp2p::swarm::behaviour::ConnectionEstablished;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use libp2p::core::{ConnectedPoint, Endpoint};
+use libp2p::swarm::behaviour::{ConnectionEstablished, DialFailure};
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
 use libp2p::swarm::{
     ConnectionClosed,
     ConnectionDenied,
@@ -23,6 +27,8 @@ use libp2p::swarm::{
     ToSwarm,
 };
 use libp2p::{Multiaddr, PeerId, StreamProtocol};
+use papyrus_common::metrics::PAPYRUS_NETWORK_DROPPED_EVENTS;
+use tracing::debug;
 
 use super::handler::{
     Handler,
@@ -30,7 +36,15 @@ use super::handler::{
     RequestToBehaviourEvent,
     SessionError as HandlerSessionError,
 };
-use super::{Bytes, Config, GenericEvent, InboundSessionId, OutboundSessionId, SessionId};
+use super::{
+    Bytes,
+    Config,
+    GenericEvent,
+    InboundSessionId,
+    OutboundSessionId,
+    PendingEventsOverflowPolicy,
+    SessionId,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum SessionError {
@@ -42,12 +56,21 @@ pub enum SessionError {
     RemoteDoesntSupportProtocol,
     #[error("In an inbound session, remote peer sent data after sending the query.")]
     OtherOutboundPeerSentData,
+    #[error(
+        "Remote peer sent a message of {frame_bytes} bytes, exceeding the maximum of \
+         {max_frame_bytes} bytes."
+    )]
+    FrameTooLarge { frame_bytes: usize, max_frame_bytes: usize },
     // If there's a connection with a single session and it was closed because of another reason,
     // we might get ConnectionClosed instead of that reason because the swarm automatically closes
     // a connection that has no sessions. If this is a problem, set the swarm's
     // idle_connection_timeout to a non-zero number.
     #[error("Connection to remote peer closed.")]
     ConnectionClosed,
+    #[error("Failed to connect to remote peer within {} seconds.", dial_timeout.as_secs())]
+    DialTimeout { dial_timeout: Duration },
+    #[error("Failed to connect to remote peer.")]
+    DialFailed,
 }
 
 impl From<GenericEvent<HandlerSessionError>> for GenericEvent<SessionError> {
@@ -82,6 +105,13 @@ impl From<GenericEvent<HandlerSessionError>> for GenericEvent<SessionError> {
                 session_id,
                 error: HandlerSessionError::OtherOutboundPeerSentData,
             } => Self::SessionFailed { session_id, error: SessionError::OtherOutboundPeerSentData },
+            GenericEvent::SessionFailed {
+                session_id,
+                error: HandlerSessionError::FrameTooLarge { frame_bytes, max_frame_bytes },
+            } => Self::SessionFailed {
+                session_id,
+                error: SessionError::FrameTooLarge { frame_bytes, max_frame_bytes },
+            },
             GenericEvent::SessionFinishedSuccessfully { session_id } => {
                 Self::SessionFinishedSuccessfully { session_id }
             }
@@ -95,16 +125,17 @@ pub type Event = GenericEvent<SessionError>;
 #[error("The given session ID doesn't exist.")]
 pub struct SessionIdNotFoundError;
 
-#[derive(thiserror::Error, Debug)]
-#[error("We are not connected to the given peer. Dial to the given peer and try again.")]
-pub struct PeerNotConnected;
-
-// TODO(shahak) remove allow dead code.
-#[allow(dead_code)]
 pub struct Behaviour {
     config: Config,
     pending_events: VecDeque<ToSwarm<Event, RequestFromBehaviourEvent>>,
-    pending_queries: DefaultHashMap<PeerId, Vec<(Bytes, OutboundSessionId)>>,
+    // Queries to peers we're not connected to. They're sent once a connection is established.
+    pending_queries: DefaultHashMap<PeerId, Vec<(Bytes, OutboundSessionId, StreamProtocol)>>,
+    peers_waiting_for_dial: VecDeque<PeerId>,
+    // The connection id of the ongoing dial to each peer.
+    dialing_peers: HashMap<PeerId, ConnectionId>,
+    dial_timeouts: FuturesUnordered<BoxFuture<'static, (PeerId, ConnectionId)>>,
+    // The addresses we know for each peer. They're given to the swarm when dialing the peer.
+    peer_addresses: DefaultHashMap<PeerId, Vec<Multiaddr>>,
     connection_ids_map: DefaultHashMap<PeerId, HashSet<ConnectionId>>,
     session_id_to_peer_id_and_connection_id: HashMap<SessionId, (PeerId, ConnectionId)>,
     next_outbound_session_id: OutboundSessionId,
@@ -119,6 +150,10 @@ impl Behaviour {
             config,
             pending_events: Default::default(),
             pending_queries: Default::default(),
+            peers_waiting_for_dial: Default::default(),
+            dialing_peers: Default::default(),
+            dial_timeouts: Default::default(),
+            peer_addresses: Default::default(),
             connection_ids_map: Default::default(),
             session_id_to_peer_id_and_connection_id: Default::default(),
             next_outbound_session_id: Default::default(),
@@ -130,32 +165,86 @@ impl Behaviour {
 
     /// Send query to the given peer and start a new outbound session with it. Return the id of the
     /// new session.
+    ///
+    /// If we're not connected to the peer, dial it and send the query once the connection is
+    /// established. If the dial fails, the session fails with [`SessionError::DialFailed`], and if
+    /// the connection isn't established within the configured dial timeout, the session fails with
+    /// [`SessionError::DialTimeout`].
     pub fn send_query(
         &mut self,
         query: Bytes,
         peer_id: PeerId,
         protocol_name: StreamProtocol,
-    ) -> Result<OutboundSessionId, PeerNotConnected> {
-        let connection_id =
-            *self.connection_ids_map.get(peer_id).iter().next().ok_or(PeerNotConnected)?;
-
+    ) -> OutboundSessionId {
         let outbound_session_id = self.next_outbound_session_id;
         self.next_outbound_session_id.value += 1;
 
-        self.session_id_to_peer_id_and_connection_id
-            .insert(outbound_session_id.into(), (peer_id, connection_id));
+        let Some(connection_id) = self.connection_ids_map.get(peer_id).iter().next().copied()
+        else {
+            self.pending_queries.get_mut(peer_id).push((query, outbound_session_id, protocol_name));
+            if !self.dialing_peers.contains_key(&peer_id)
+                && !self.peers_waiting_for_dial.contains(&peer_id)
+            {
+                self.peers_waiting_for_dial.push_back(peer_id);
+                self.dial_waiting_peers();
+            }
+            return outbound_session_id;
+        };
 
-        self.add_event_to_queue(ToSwarm::NotifyHandler {
+        self.create_outbound_session(
+            query,
             peer_id,
-            handler: NotifyHandler::One(connection_id),
-            event: RequestFromBehaviourEvent::CreateOutboundSession {
-                query,
-                outbound_session_id,
-                protocol_name,
-            },
-        });
+            connection_id,
+            outbound_session_id,
+            protocol_name,
+        );
+        outbound_session_id
+    }
+
+    /// Send all the given queries to the given peer, each in a new outbound session. Return the ids
+    /// of the new sessions in the order of the queries.
+    ///
+    /// If we're not connected to the peer, dial it once and send all the queries once the
+    /// connection is established.
+    pub fn send_queries(
+        &mut self,
+        queries: Vec<Bytes>,
+        peer_id: PeerId,
+        protocol_name: StreamProtocol,
+    ) -> Vec<OutboundSessionId> {
+        queries
+            .into_iter()
+            .map(|query| self.send_query(query, peer_id, protocol_name.clone()))
+            .collect()
+    }
+
+    /// Return whether there's an open connection to the given peer. If there isn't, sending a query
+    /// to the peer dials it first.
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        !self.connection_ids_map.get(*peer_id).is_empty()
+    }
 
-        Ok(outbound_session_id)
+    /// Return the peers that there's an open connection to.
+    pub fn connected_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.connection_ids_map.keys()
+    }
+
+    /// Return the number of queries that are waiting for a connection to their peer.
+    pub fn pending_query_count(&self) -> usize {
+        self.pending_queries.values().map(Vec::len).sum()
+    }
+
+    /// Return the number of queries to the given peer that are waiting for a connection to it.
+    pub fn pending_query_count_for(&self, peer_id: PeerId) -> usize {
+        self.pending_queries.get(peer_id).len()
+    }
+
+    /// Add an address of the given peer. The known addresses of a peer are used when dialing it.
+    pub fn add_address(&mut self, peer_id: PeerId, address: Multiaddr) {
+        let addresses = self.peer_addresses.get_mut(peer_id);
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
     }
 
     /// Send a data message to an open inbound session.
@@ -205,6 +294,77 @@ impl Behaviour {
         Ok(())
     }
 
+    fn create_outbound_session(
+        &mut self,
+        query: Bytes,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        outbound_session_id: OutboundSessionId,
+        protocol_name: StreamProtocol,
+    ) {
+        self.session_id_to_peer_id_and_connection_id
+            .insert(outbound_session_id.into(), (peer_id, connection_id));
+
+        self.add_event_to_queue(ToSwarm::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::One(connection_id),
+            event: RequestFromBehaviourEvent::CreateOutboundSession {
+                query,
+                outbound_session_id,
+                protocol_name,
+            },
+        });
+    }
+
+    /// Dial the peers waiting for a dial, as long as there are less than `max_concurrent_dials`
+    /// ongoing dials.
+    fn dial_waiting_peers(&mut self) {
+        while self.dialing_peers.len() < self.config.max_concurrent_dials {
+            let Some(peer_id) = self.peers_waiting_for_dial.pop_front() else {
+                return;
+            };
+            let opts = Self::dial_opts_for(peer_id);
+            let dial_connection_id = opts.connection_id();
+            self.dialing_peers.insert(peer_id, dial_connection_id);
+            self.dial_timeouts.push(
+                tokio::time::sleep(self.config.dial_timeout)
+                    .map(move |()| (peer_id, dial_connection_id))
+                    .boxed(),
+            );
+            self.add_event_to_queue(ToSwarm::Dial { opts });
+        }
+    }
+
+    /// The options of the dial to a peer that we have queries for. The dial is skipped if we got
+    /// connected to the peer in the meantime, but not if there's another ongoing dial to it. The
+    /// swarm gets the addresses to dial from [`Behaviour::add_address`] through
+    /// `handle_pending_outbound_connection`.
+    fn dial_opts_for(peer_id: PeerId) -> DialOpts {
+        DialOpts::peer_id(peer_id).condition(PeerCondition::Disconnected).build()
+    }
+
+    /// Fail the queries that wait for the given dial to their peer. Does nothing if the dial
+    /// already succeeded or failed.
+    fn handle_failed_dial(
+        &mut self,
+        peer_id: PeerId,
+        dial_connection_id: ConnectionId,
+        error: impl Fn() -> SessionError,
+    ) {
+        if self.dialing_peers.get(&peer_id) != Some(&dial_connection_id) {
+            return;
+        }
+        self.dialing_peers.remove(&peer_id);
+        for (_, outbound_session_id, _) in self.pending_queries.remove(&peer_id).unwrap_or_default()
+        {
+            self.add_event_to_queue(ToSwarm::GenerateEvent(Event::SessionFailed {
+                session_id: outbound_session_id.into(),
+                error: error(),
+            }));
+        }
+        self.dial_waiting_peers();
+    }
+
     fn get_peer_id_and_connection_id_from_session_id(
         &self,
         session_id: SessionId,
@@ -215,7 +375,32 @@ impl Behaviour {
             .ok_or(SessionIdNotFoundError)
     }
 
+    /// Add an event to the pending events. If the pending events are full, a dial is dropped
+    /// according to the overflow policy. Only dials are dropped. All other events are exempt from
+    /// the limit: requests to the connection handlers (creating, closing and dropping sessions and
+    /// sending data on them) and the events generated to the swarm (new sessions, received data
+    /// and finished or failed sessions). Dropping them would leave their sessions stuck, while the
+    /// queries that wait for a dropped dial fail once the dial times out.
     fn add_event_to_queue(&mut self, event: ToSwarm<Event, RequestFromBehaviourEvent>) {
+        if self
+            .config
+            .max_pending_events
+            .is_some_and(|max_pending_events| self.pending_events.len() >= max_pending_events)
+        {
+            let oldest_dial_index =
+                self.pending_events.iter().position(|event| matches!(event, ToSwarm::Dial { .. }));
+            match (self.config.pending_events_overflow_policy, oldest_dial_index, &event) {
+                (PendingEventsOverflowPolicy::DropOldest, Some(oldest_dial_index), _) => {
+                    self.pending_events.remove(oldest_dial_index);
+                    metrics::increment_counter!(PAPYRUS_NETWORK_DROPPED_EVENTS);
+                }
+                (_, _, ToSwarm::Dial { .. }) => {
+                    metrics::increment_counter!(PAPYRUS_NETWORK_DROPPED_EVENTS);
+                    return;
+                }
+                _ => {}
+            }
+        }
         self.pending_events.push_back(event);
         for waker in self.wakers_waiting_for_event.drain(..) {
             waker.wake();
@@ -237,6 +422,16 @@ impl NetworkBehaviour for Behaviour {
         Ok(Handler::new(self.config.clone(), self.next_inbound_session_id.clone(), peer_id))
     }
 
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        _addresses: &[Multiaddr],
+        _effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        Ok(maybe_peer.map(|peer_id| self.peer_addresses.get(peer_id).clone()).unwrap_or_default())
+    }
+
     fn handle_established_outbound_connection(
         &mut self,
         _connection_id: ConnectionId,
@@ -252,11 +447,35 @@ impl NetworkBehaviour for Behaviour {
             FromSwarm::ConnectionEstablished(ConnectionEstablished {
                 peer_id,
                 connection_id,
+                endpoint,
                 ..
             }) => {
+                if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                    self.add_address(peer_id, address.clone());
+                }
                 self.connection_ids_map.get_mut(peer_id).insert(connection_id);
+                self.peers_waiting_for_dial.retain(|waiting_peer_id| *waiting_peer_id != peer_id);
+                if self.dialing_peers.remove(&peer_id).is_some() {
+                    self.dial_waiting_peers();
+                }
+                for (query, outbound_session_id, protocol_name) in
+                    self.pending_queries.remove(&peer_id).unwrap_or_default()
+                {
+                    self.create_outbound_session(
+                        query,
+                        peer_id,
+                        connection_id,
+                        outbound_session_id,
+                        protocol_name,
+                    );
+                }
             }
             FromSwarm::ConnectionClosed(ConnectionClosed { peer_id, connection_id, .. }) => {
+                let connection_ids = self.connection_ids_map.get_mut(peer_id);
+                connection_ids.remove(&connection_id);
+                if connection_ids.is_empty() {
+                    self.connection_ids_map.remove(&peer_id);
+                }
                 let mut session_ids = Vec::new();
                 self.session_id_to_peer_id_and_connection_id.retain(
                     |session_id, (session_peer_id, session_connection_id)| {
@@ -275,6 +494,14 @@ impl NetworkBehaviour for Behaviour {
                     }));
                 }
             }
+            FromSwarm::DialFailure(DialFailure {
+                peer_id: Some(peer_id),
+                connection_id,
+                error,
+            }) => {
+                debug!("Failed dialing peer {peer_id:?}: {error:?}");
+                self.handle_failed_dial(peer_id, connection_id, || SessionError::DialFailed);
+            }
             _ => {}
         }
     }
@@ -323,6 +550,15 @@ impl NetworkBehaviour for Behaviour {
         cx: &mut Context<'_>,
     ) -> Poll<ToSwarm<Self::ToSwarm, <Self::ConnectionHandler as ConnectionHandler>::FromBehaviour>>
     {
+        while let Poll::Ready(Some((peer_id, dial_connection_id))) =
+            self.dial_timeouts.poll_next_unpin(cx)
+        {
+            // The dial might have already succeeded, in which case the timeout is irrelevant.
+            let dial_timeout = self.config.dial_timeout;
+            self.handle_failed_dial(peer_id, dial_connection_id, || SessionError::DialTimeout {
+                dial_timeout,
+            });
+        }
         if let Some(event) = self.pending_events.pop_front() {
             return Poll::Ready(event);
         }
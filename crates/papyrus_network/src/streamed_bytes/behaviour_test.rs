@@ -1,25 +1,46 @@
+use std::collections::HashSet;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use assert_matches::assert_matches;
 use futures::{FutureExt, Stream, StreamExt};
 use lazy_static::lazy_static;
 use libp2p::core::{ConnectedPoint, Endpoint};
-use libp2p::swarm::behaviour::ConnectionEstablished;
+use libp2p::swarm::behaviour::{ConnectionEstablished, DialFailure};
+use libp2p::swarm::dial_opts::PeerCondition;
 use libp2p::swarm::{
+    dummy,
     ConnectionClosed,
     ConnectionId,
+    DialError,
     FromSwarm,
     NetworkBehaviour,
+    NotifyHandler,
     StreamProtocol,
+    Swarm,
+    SwarmEvent,
     ToSwarm,
 };
 use libp2p::{Multiaddr, PeerId};
+use libp2p_swarm_test::SwarmExt;
+use papyrus_common::metrics::PAPYRUS_NETWORK_DROPPED_EVENTS;
+use prometheus_parse::Value::Counter;
+use test_utils::prometheus_is_contained;
+use tokio::time::Instant;
 
 use super::super::handler::{RequestFromBehaviourEvent, RequestToBehaviourEvent};
-use super::super::{Bytes, Config, GenericEvent, InboundSessionId, OutboundSessionId, SessionId};
+use super::super::{
+    Bytes,
+    Config,
+    GenericEvent,
+    InboundSessionId,
+    OutboundSessionId,
+    PendingEventsOverflowPolicy,
+    SessionId,
+};
 use super::{Behaviour, Event, SessionError};
-use crate::test_utils::dummy_data;
+use crate::test_utils::{dummy_data, PROMETHEUS_HANDLE};
 
 impl Unpin for Behaviour {}
 
@@ -271,6 +292,14 @@ async fn validate_session_finished_successfully_event(
     );
 }
 
+async fn validate_dial_event(behaviour: &mut Behaviour, peer_id: &PeerId) {
+    let event = behaviour.next().await.unwrap();
+    assert_matches!(
+        event,
+        ToSwarm::Dial { opts } if opts.get_peer_id() == Some(*peer_id)
+    );
+}
+
 // TODO(shahak): Fix code duplication with handler test.
 fn validate_no_events(behaviour: &mut Behaviour) {
     assert!(behaviour.next().now_or_never().is_none());
@@ -317,8 +346,7 @@ async fn create_and_process_outbound_session() {
     let peer_id = PeerId::random();
 
     simulate_connection_established(&mut behaviour, peer_id);
-    let outbound_session_id =
-        behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone()).unwrap();
+    let outbound_session_id = behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone());
 
     validate_create_outbound_session_event(&mut behaviour, &peer_id, &QUERY, &outbound_session_id)
         .await;
@@ -349,8 +377,7 @@ async fn connection_closed() {
 
     simulate_connection_established(&mut behaviour, peer_id);
 
-    let outbound_session_id =
-        behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone()).unwrap();
+    let outbound_session_id = behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone());
 
     // Consume the event to create an outbound session.
     behaviour.next().await.unwrap();
@@ -397,8 +424,7 @@ async fn drop_outbound_session() {
 
     simulate_connection_established(&mut behaviour, peer_id);
 
-    let outbound_session_id =
-        behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone()).unwrap();
+    let outbound_session_id = behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone());
 
     // Consume the event to create an outbound session.
     behaviour.next().await.unwrap();
@@ -449,6 +475,92 @@ async fn drop_inbound_session() {
     // so if it will the behaviour might output them.
 }
 
+#[tokio::test]
+async fn sessions_are_bound_to_a_single_connection() {
+    let mut behaviour = Behaviour::new(Config::get_test_config());
+
+    let peer_id = PeerId::random();
+    let first_connection_id = ConnectionId::new_unchecked(0);
+    let second_connection_id = ConnectionId::new_unchecked(1);
+    for (other_established, connection_id) in
+        [first_connection_id, second_connection_id].into_iter().enumerate()
+    {
+        let address = Multiaddr::empty();
+        let role_override = Endpoint::Dialer;
+        let _handler = behaviour
+            .handle_established_outbound_connection(connection_id, peer_id, &address, role_override)
+            .unwrap();
+        behaviour.on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+            peer_id,
+            connection_id,
+            endpoint: &ConnectedPoint::Dialer { address, role_override },
+            failed_addresses: &[],
+            other_established,
+        }));
+    }
+
+    // An inbound session is bound to the connection it was opened on.
+    let inbound_session_id = InboundSessionId::default();
+    behaviour.on_connection_handler_event(
+        peer_id,
+        second_connection_id,
+        RequestToBehaviourEvent::GenerateEvent(GenericEvent::NewInboundSession {
+            query: QUERY.clone(),
+            inbound_session_id,
+            peer_id,
+            protocol_name: PROTOCOL_NAME.clone(),
+        }),
+    );
+    validate_new_inbound_session_event(&mut behaviour, &peer_id, inbound_session_id, &QUERY).await;
+    for data in dummy_data() {
+        behaviour.send_data(data, inbound_session_id).unwrap();
+    }
+    behaviour.close_inbound_session(inbound_session_id).unwrap();
+    for _ in 0..(dummy_data().len() + 1) {
+        let event = behaviour.next().await.unwrap();
+        assert_matches!(
+            event,
+            ToSwarm::NotifyHandler { handler: NotifyHandler::One(connection_id), .. }
+                if connection_id == second_connection_id
+        );
+    }
+
+    // An outbound session is bound to the connection it was created on.
+    let outbound_session_id = behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone());
+    let ToSwarm::NotifyHandler {
+        handler: NotifyHandler::One(outbound_connection_id),
+        event: RequestFromBehaviourEvent::CreateOutboundSession { .. },
+        ..
+    } = behaviour.next().await.unwrap()
+    else {
+        panic!("Expected a CreateOutboundSession event sent to a single connection");
+    };
+    behaviour.drop_session(outbound_session_id.into()).unwrap();
+    let event = behaviour.next().await.unwrap();
+    assert_matches!(
+        event,
+        ToSwarm::NotifyHandler { handler: NotifyHandler::One(connection_id), .. }
+            if connection_id == outbound_connection_id
+    );
+    validate_no_events(&mut behaviour);
+}
+
+#[test]
+fn is_connected_until_connection_closed() {
+    let mut behaviour = Behaviour::new(Config::get_test_config());
+
+    let peer_id = PeerId::random();
+    assert!(!behaviour.is_connected(&peer_id));
+
+    simulate_connection_established(&mut behaviour, peer_id);
+    assert!(behaviour.is_connected(&peer_id));
+    assert_eq!(behaviour.connected_peers().collect::<Vec<_>>(), vec![&peer_id]);
+
+    simulate_connection_closed(&mut behaviour, peer_id);
+    assert!(!behaviour.is_connected(&peer_id));
+    assert_eq!(behaviour.connected_peers().count(), 0);
+}
+
 #[test]
 fn close_non_existing_session_fails() {
     let mut behaviour = Behaviour::new(Config::get_test_config());
@@ -463,11 +575,285 @@ fn send_data_non_existing_session_fails() {
     }
 }
 
-#[test]
-fn send_query_peer_not_connected_fails() {
+#[tokio::test]
+async fn send_query_peer_not_connected_dials_and_sends_once_connected() {
+    let mut behaviour = Behaviour::new(Config::get_test_config());
+
+    let peer_id = PeerId::random();
+
+    let outbound_session_id = behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone());
+    validate_dial_event(&mut behaviour, &peer_id).await;
+    validate_no_events(&mut behaviour);
+
+    simulate_connection_established(&mut behaviour, peer_id);
+    validate_create_outbound_session_event(&mut behaviour, &peer_id, &QUERY, &outbound_session_id)
+        .await;
+    validate_no_events(&mut behaviour);
+}
+
+#[tokio::test]
+async fn send_queries_peer_not_connected_dials_once_and_sends_all_once_connected() {
+    let mut behaviour = Behaviour::new(Config::get_test_config());
+
+    let peer_id = PeerId::random();
+    let queries = dummy_data();
+
+    let outbound_session_ids =
+        behaviour.send_queries(queries.clone(), peer_id, PROTOCOL_NAME.clone());
+    assert_eq!(outbound_session_ids.len(), queries.len());
+    assert_eq!(outbound_session_ids.iter().collect::<HashSet<_>>().len(), queries.len());
+    validate_dial_event(&mut behaviour, &peer_id).await;
+    validate_no_events(&mut behaviour);
+
+    simulate_connection_established(&mut behaviour, peer_id);
+    for (query, outbound_session_id) in queries.iter().zip(&outbound_session_ids) {
+        validate_create_outbound_session_event(
+            &mut behaviour,
+            &peer_id,
+            query,
+            outbound_session_id,
+        )
+        .await;
+    }
+    validate_no_events(&mut behaviour);
+}
+
+#[tokio::test]
+async fn pending_query_count_of_peers_not_connected() {
     let mut behaviour = Behaviour::new(Config::get_test_config());
 
+    let peer_id1 = PeerId::random();
+    let peer_id2 = PeerId::random();
+
+    behaviour.send_queries(dummy_data(), peer_id1, PROTOCOL_NAME.clone());
+    behaviour.send_query(QUERY.clone(), peer_id2, PROTOCOL_NAME.clone());
+    assert_eq!(behaviour.pending_query_count(), dummy_data().len() + 1);
+    assert_eq!(behaviour.pending_query_count_for(peer_id1), dummy_data().len());
+    assert_eq!(behaviour.pending_query_count_for(peer_id2), 1);
+    assert_eq!(behaviour.pending_query_count_for(PeerId::random()), 0);
+
+    simulate_connection_established(&mut behaviour, peer_id1);
+    assert_eq!(behaviour.pending_query_count(), 1);
+    assert_eq!(behaviour.pending_query_count_for(peer_id1), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn send_query_fails_on_dial_timeout() {
+    const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+    let mut behaviour =
+        Behaviour::new(Config { dial_timeout: DIAL_TIMEOUT, ..Config::get_test_config() });
+
     let peer_id = PeerId::random();
 
-    behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone()).unwrap_err();
+    let start_time = Instant::now();
+    let outbound_session_id = behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone());
+    validate_dial_event(&mut behaviour, &peer_id).await;
+
+    let event = behaviour.next().await.unwrap();
+    assert_eq!(start_time.elapsed(), DIAL_TIMEOUT);
+    assert_matches!(
+        event,
+        ToSwarm::GenerateEvent(Event::SessionFailed {
+            session_id,
+            error: SessionError::DialTimeout { dial_timeout },
+        }) if session_id == outbound_session_id.into() && dial_timeout == DIAL_TIMEOUT
+    );
+    validate_no_events(&mut behaviour);
+}
+
+#[tokio::test(start_paused = true)]
+async fn send_query_fails_once_dial_fails() {
+    let mut behaviour = Behaviour::new(Config::get_test_config());
+
+    let peer_id = PeerId::random();
+
+    let start_time = Instant::now();
+    let outbound_session_id = behaviour.send_query(QUERY.clone(), peer_id, PROTOCOL_NAME.clone());
+    let event = behaviour.next().await.unwrap();
+    let ToSwarm::Dial { opts } = event else {
+        panic!("Expected a dial event, got {event:?}");
+    };
+
+    // A failure of another dial to the peer doesn't affect the query.
+    behaviour.on_swarm_event(FromSwarm::DialFailure(DialFailure {
+        peer_id: Some(peer_id),
+        error: &DialError::Aborted,
+        connection_id: ConnectionId::new_unchecked(usize::MAX),
+    }));
+    validate_no_events(&mut behaviour);
+
+    behaviour.on_swarm_event(FromSwarm::DialFailure(DialFailure {
+        peer_id: Some(peer_id),
+        error: &DialError::Aborted,
+        connection_id: opts.connection_id(),
+    }));
+    let event = behaviour.next().await.unwrap();
+    assert_eq!(start_time.elapsed(), Duration::ZERO);
+    assert_matches!(
+        event,
+        ToSwarm::GenerateEvent(Event::SessionFailed {
+            session_id,
+            error: SessionError::DialFailed,
+        }) if session_id == outbound_session_id.into()
+    );
+    validate_no_events(&mut behaviour);
+}
+
+#[tokio::test]
+async fn send_query_limits_concurrent_dials() {
+    let mut behaviour =
+        Behaviour::new(Config { max_concurrent_dials: 1, ..Config::get_test_config() });
+
+    let peer_id1 = PeerId::random();
+    let peer_id2 = PeerId::random();
+
+    behaviour.send_query(QUERY.clone(), peer_id1, PROTOCOL_NAME.clone());
+    behaviour.send_query(QUERY.clone(), peer_id2, PROTOCOL_NAME.clone());
+    validate_dial_event(&mut behaviour, &peer_id1).await;
+    validate_no_events(&mut behaviour);
+
+    simulate_connection_established(&mut behaviour, peer_id1);
+    validate_dial_event(&mut behaviour, &peer_id2).await;
+}
+
+// Both overflow policies are checked in the same test since the metric of dropped events is global.
+#[tokio::test(start_paused = true)]
+async fn pending_events_overflow_drops_only_dials() {
+    for (pending_events_overflow_policy, dialed_peer_indices, num_dropped_events) in [
+        (PendingEventsOverflowPolicy::DropOldest, [1, 2], 1),
+        (PendingEventsOverflowPolicy::RejectNew, [0, 1], 2),
+    ] {
+        let mut behaviour = Behaviour::new(Config {
+            max_pending_events: Some(2),
+            pending_events_overflow_policy,
+            ..Config::get_test_config()
+        });
+
+        // Events of sessions, both requests to handlers and events to the swarm, aren't dropped
+        // even if there are more of them than the limit.
+        let connected_peer_id = PeerId::random();
+        simulate_connection_established(&mut behaviour, connected_peer_id);
+        let outbound_session_ids = (0..3)
+            .map(|_| behaviour.send_query(QUERY.clone(), connected_peer_id, PROTOCOL_NAME.clone()))
+            .collect::<Vec<_>>();
+        simulate_received_data(
+            &mut behaviour,
+            connected_peer_id,
+            QUERY.clone(),
+            outbound_session_ids[0],
+        );
+        let inbound_session_id = InboundSessionId::default();
+        simulate_new_inbound_session(
+            &mut behaviour,
+            connected_peer_id,
+            inbound_session_id,
+            QUERY.clone(),
+        );
+        behaviour.send_data(QUERY.clone(), inbound_session_id).unwrap();
+        for outbound_session_id in &outbound_session_ids {
+            validate_create_outbound_session_event(
+                &mut behaviour,
+                &connected_peer_id,
+                &QUERY,
+                outbound_session_id,
+            )
+            .await;
+        }
+        validate_received_data_event(&mut behaviour, &QUERY, outbound_session_ids[0]).await;
+        validate_new_inbound_session_event(
+            &mut behaviour,
+            &connected_peer_id,
+            inbound_session_id,
+            &QUERY,
+        )
+        .await;
+        validate_request_send_data_event(
+            &mut behaviour,
+            &connected_peer_id,
+            &QUERY,
+            inbound_session_id,
+        )
+        .await;
+        validate_no_events(&mut behaviour);
+
+        let peer_ids = (0..3).map(|_| PeerId::random()).collect::<Vec<_>>();
+        let outbound_session_ids = peer_ids
+            .iter()
+            .map(|peer_id| behaviour.send_query(QUERY.clone(), *peer_id, PROTOCOL_NAME.clone()))
+            .collect::<HashSet<_>>();
+        for peer_index in dialed_peer_indices {
+            validate_dial_event(&mut behaviour, &peer_ids[peer_index]).await;
+        }
+        validate_no_events(&mut behaviour);
+        assert_eq!(
+            prometheus_is_contained(
+                PROMETHEUS_HANDLE.render(),
+                PAPYRUS_NETWORK_DROPPED_EVENTS,
+                &[]
+            ),
+            Some(Counter(num_dropped_events as f64))
+        );
+
+        // The query whose dial was dropped fails on dial timeout like the others.
+        let mut failed_session_ids = HashSet::new();
+        for _ in 0..outbound_session_ids.len() {
+            let event = behaviour.next().await.unwrap();
+            let ToSwarm::GenerateEvent(Event::SessionFailed {
+                session_id: SessionId::OutboundSessionId(outbound_session_id),
+                error: SessionError::DialTimeout { .. },
+            }) = event
+            else {
+                panic!("Expected a dial timeout event, got {event:?}");
+            };
+            failed_session_ids.insert(outbound_session_id);
+        }
+        assert_eq!(failed_session_ids, outbound_session_ids);
+    }
+}
+
+#[tokio::test]
+async fn queued_query_is_sent_after_dialing_the_peer_at_its_known_address() {
+    let mut swarm = Swarm::new_ephemeral(|_| Behaviour::new(Config::get_test_config()));
+    let mut other_swarm = Swarm::new_ephemeral(|_| Behaviour::new(Config::get_test_config()));
+    other_swarm.listen().with_memory_addr_external().await;
+    let other_peer_id = *other_swarm.local_peer_id();
+    let other_address = other_swarm.external_addresses().next().unwrap().clone();
+
+    swarm.behaviour_mut().add_address(other_peer_id, other_address);
+    swarm.behaviour_mut().send_query(QUERY.clone(), other_peer_id, PROTOCOL_NAME.clone());
+    assert_eq!(swarm.behaviour().pending_query_count_for(other_peer_id), 1);
+
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => {
+                if let SwarmEvent::Behaviour(Event::SessionFailed { error, .. }) = event {
+                    panic!("The query failed with {error:?}");
+                }
+            }
+            event = other_swarm.select_next_some() => {
+                if let SwarmEvent::Behaviour(Event::NewInboundSession { query, peer_id, .. }) = event {
+                    assert_eq!(query, *QUERY);
+                    assert_eq!(peer_id, *swarm.local_peer_id());
+                    break;
+                }
+            }
+        }
+    }
+    assert!(swarm.behaviour().is_connected(&other_peer_id));
+    assert_eq!(swarm.behaviour().pending_query_count_for(other_peer_id), 0);
+}
+
+#[tokio::test]
+async fn dial_opts_dont_target_connected_peers() {
+    let mut swarm = Swarm::new_ephemeral(|_| dummy::Behaviour);
+    let mut other_swarm = Swarm::new_ephemeral(|_| dummy::Behaviour);
+    swarm.listen().with_memory_addr_external().await;
+    other_swarm.listen().with_memory_addr_external().await;
+    let other_peer_id = *other_swarm.local_peer_id();
+
+    swarm.connect(&mut other_swarm).await;
+    assert_matches!(
+        swarm.dial(Behaviour::dial_opts_for(other_peer_id)),
+        Err(DialError::DialPeerConditionFalse(PeerCondition::Disconnected))
+    );
 }
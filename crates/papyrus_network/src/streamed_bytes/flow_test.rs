@@ -9,7 +9,17 @@ use libp2p::swarm::{NetworkBehaviour, StreamProtocol, SwarmEvent};
 use libp2p::{PeerId, Swarm};
 
 use super::behaviour::{Behaviour, Event};
-use super::{Bytes, Config, InboundSessionId, OutboundSessionId, SessionId};
+use super::{
+    Bytes,
+    Config,
+    InboundSessionId,
+    OutboundSessionId,
+    SessionId,
+    DEFAULT_DIAL_TIMEOUT,
+    DEFAULT_MAX_CONCURRENT_DIALS,
+    DEFAULT_MAX_FRAME_BYTES,
+    DEFAULT_MAX_INFLIGHT_BYTES,
+};
 use crate::test_utils::{create_fully_connected_swarms_stream, StreamHashMap};
 
 const NUM_PEERS: usize = 3;
@@ -64,14 +74,11 @@ fn send_query_and_update_map(
     outbound_session_id_to_peer_id: &mut HashMap<(PeerId, OutboundSessionId), PeerId>,
 ) {
     let outbound_peer_id = *outbound_swarm.local_peer_id();
-    let outbound_session_id = outbound_swarm
-        .behaviour_mut()
-        .send_query(
-            get_bytes_from_query_indices(outbound_peer_id, inbound_peer_id),
-            inbound_peer_id,
-            PROTOCOL_NAME,
-        )
-        .unwrap();
+    let outbound_session_id = outbound_swarm.behaviour_mut().send_query(
+        get_bytes_from_query_indices(outbound_peer_id, inbound_peer_id),
+        inbound_peer_id,
+        PROTOCOL_NAME,
+    );
     outbound_session_id_to_peer_id.insert((outbound_peer_id, outbound_session_id), inbound_peer_id);
 }
 
@@ -180,6 +187,13 @@ async fn everyone_sends_to_everyone() {
         Behaviour::new(Config {
             session_timeout: Duration::from_secs(5),
             supported_inbound_protocols: vec![PROTOCOL_NAME, OTHER_PROTOCOL_NAME],
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_inflight_bytes: DEFAULT_MAX_INFLIGHT_BYTES,
+            dial_timeout: DEFAULT_DIAL_TIMEOUT,
+            max_concurrent_dials: DEFAULT_MAX_CONCURRENT_DIALS,
+            max_pending_events: None,
+            pending_events_overflow_policy: Default::default(),
+            compress_data: false,
         })
     })
     .await;
@@ -4,13 +4,15 @@ mod handler_test;
 mod inbound_session;
 
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::io;
+use std::io::{self, Read};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use async_stream::stream;
+use defaultmap::DefaultHashMap;
+use flate2::bufread::GzDecoder;
 use futures::stream::BoxStream;
 use futures::{FutureExt, StreamExt};
 use libp2p::swarm::handler::{
@@ -30,7 +32,7 @@ use libp2p::PeerId;
 use tracing::debug;
 
 use self::inbound_session::InboundSession;
-use super::messages::read_message;
+use super::messages::{read_message, MessageTooLargeError};
 use super::protocol::{InboundProtocol, OutboundProtocol};
 use super::{Bytes, Config, GenericEvent, InboundSessionId, OutboundSessionId, SessionId};
 
@@ -74,6 +76,38 @@ pub enum SessionError {
     // TODO(shahak) erase this.
     #[error("In an inbound session, remote peer sent data after sending the query.")]
     OtherOutboundPeerSentData,
+    #[error(
+        "Remote peer sent a message of {frame_bytes} bytes, exceeding the maximum of \
+         {max_frame_bytes} bytes."
+    )]
+    FrameTooLarge { frame_bytes: usize, max_frame_bytes: usize },
+}
+
+impl SessionError {
+    fn from_read_message_error(error: io::Error) -> Self {
+        match error.get_ref().and_then(|inner_error| inner_error.downcast_ref()) {
+            Some(MessageTooLargeError { message_len, max_message_size }) => Self::FrameTooLarge {
+                frame_bytes: *message_len,
+                max_frame_bytes: *max_message_size,
+            },
+            None => Self::IOError(error),
+        }
+    }
+}
+
+/// Decompresses a data message that was received on a session with compression. The decompressed
+/// message is bounded by `max_frame_bytes` like an uncompressed one. Decompression stops once the
+/// bound is passed, so a small frame can't expand into an arbitrarily large message in memory.
+fn decompress_frame(frame: &Bytes, max_frame_bytes: usize) -> Result<Bytes, SessionError> {
+    let max_frame_bytes_u64 =
+        u64::try_from(max_frame_bytes).expect("Failed converting usize to u64");
+    let mut data = Vec::new();
+    GzDecoder::new(&frame[..]).take(max_frame_bytes_u64 + 1).read_to_end(&mut data)?;
+    if data.len() > max_frame_bytes {
+        // The frame is at least this large. The rest of it isn't decompressed.
+        return Err(SessionError::FrameTooLarge { frame_bytes: data.len(), max_frame_bytes });
+    }
+    Ok(data)
 }
 
 type HandlerEvent<H> = ConnectionHandlerEvent<
@@ -89,12 +123,14 @@ pub struct Handler {
     peer_id: PeerId,
     id_to_inbound_session: HashMap<InboundSessionId, InboundSession>,
     id_to_outbound_session:
-        HashMap<OutboundSessionId, BoxStream<'static, Result<Bytes, io::Error>>>,
+        HashMap<OutboundSessionId, BoxStream<'static, Result<Bytes, SessionError>>>,
     // TODO(shahak): Use deadqueue if using a VecDeque is a bug (libp2p uses VecDeque, so we opened
     // an issue on it https://github.com/libp2p/rust-libp2p/issues/5147)
     pending_events: VecDeque<HandlerEvent<Self>>,
     inbound_sessions_marked_to_end: HashSet<InboundSessionId>,
     dropped_outbound_sessions_non_negotiated: HashSet<OutboundSessionId>,
+    // The number of bytes each outbound session received that are in pending_events.
+    outbound_session_id_to_buffered_bytes: DefaultHashMap<OutboundSessionId, usize>,
 }
 
 impl Handler {
@@ -111,6 +147,7 @@ impl Handler {
             pending_events: Default::default(),
             inbound_sessions_marked_to_end: Default::default(),
             dropped_outbound_sessions_non_negotiated: Default::default(),
+            outbound_session_id_to_buffered_bytes: Default::default(),
         }
     }
 
@@ -161,7 +198,10 @@ impl ConnectionHandler for Handler {
 
     fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
         SubstreamProtocol::new(
-            InboundProtocol::new(self.config.supported_inbound_protocols.clone()),
+            InboundProtocol::new(
+                self.config.supported_inbound_protocols.clone(),
+                self.config.compress_data,
+            ),
             InboundSessionId { value: self.next_inbound_session_id.fetch_add(1, Ordering::AcqRel) },
         )
         .with_timeout(self.config.session_timeout)
@@ -203,8 +243,17 @@ impl ConnectionHandler for Handler {
 
         // Handle outbound sessions.
         self.id_to_outbound_session.retain(|outbound_session_id, outbound_session| {
+            // Stop reading from a session whose data is still in pending_events. This applies
+            // backpressure on the other peer while the behaviour doesn't poll the handler.
+            if *self.outbound_session_id_to_buffered_bytes.get(*outbound_session_id)
+                >= self.config.max_inflight_bytes
+            {
+                return true;
+            }
             match outbound_session.poll_next_unpin(cx) {
                 Poll::Ready(Some(Ok(data))) => {
+                    *self.outbound_session_id_to_buffered_bytes.get_mut(*outbound_session_id) +=
+                        data.len();
                     self.pending_events.push_back(ConnectionHandlerEvent::NotifyBehaviour(
                         RequestToBehaviourEvent::GenerateEvent(GenericEvent::ReceivedData {
                             outbound_session_id: *outbound_session_id,
@@ -213,11 +262,11 @@ impl ConnectionHandler for Handler {
                     ));
                     true
                 }
-                Poll::Ready(Some(Err(io_error))) => {
+                Poll::Ready(Some(Err(session_error))) => {
                     self.pending_events.push_back(ConnectionHandlerEvent::NotifyBehaviour(
                         RequestToBehaviourEvent::GenerateEvent(GenericEvent::SessionFailed {
                             session_id: SessionId::OutboundSessionId(*outbound_session_id),
-                            error: SessionError::IOError(io_error),
+                            error: session_error,
                         }),
                     ));
                     false
@@ -239,6 +288,24 @@ impl ConnectionHandler for Handler {
         // Handling pending_events at the end of the function to avoid starvation and to make sure
         // we don't return Pending if the code above created an event.
         if let Some(event) = self.pending_events.pop_front() {
+            if let ConnectionHandlerEvent::NotifyBehaviour(
+                RequestToBehaviourEvent::GenerateEvent(GenericEvent::ReceivedData {
+                    outbound_session_id,
+                    data,
+                }),
+            ) = &event
+            {
+                let buffered_bytes = self
+                    .outbound_session_id_to_buffered_bytes
+                    .get(*outbound_session_id)
+                    .saturating_sub(data.len());
+                if buffered_bytes == 0 {
+                    self.outbound_session_id_to_buffered_bytes.remove(outbound_session_id);
+                } else {
+                    self.outbound_session_id_to_buffered_bytes
+                        .insert(*outbound_session_id, buffered_bytes);
+                }
+            }
             return Poll::Ready(event);
         }
         Poll::Pending
@@ -258,7 +325,11 @@ impl ConnectionHandler for Handler {
                 // on_behaviour_event. See https://github.com/libp2p/rust-libp2p/issues/5147
                 self.pending_events.push_back(ConnectionHandlerEvent::OutboundSubstreamRequest {
                     protocol: SubstreamProtocol::new(
-                        OutboundProtocol { query, protocol_name },
+                        OutboundProtocol {
+                            query,
+                            protocol_name,
+                            compress_data: self.config.compress_data,
+                        },
                         outbound_session_id,
                     )
                     .with_timeout(self.config.session_timeout),
@@ -331,21 +402,25 @@ impl ConnectionHandler for Handler {
     ) {
         match event {
             ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
-                protocol: mut read_stream,
+                protocol: (mut read_stream, is_compressed),
                 info: outbound_session_id,
             }) => {
                 if self.dropped_outbound_sessions_non_negotiated.remove(&outbound_session_id) {
                     return;
                 }
+                let max_frame_bytes = self.config.max_frame_bytes;
                 self.id_to_outbound_session.insert(
                     outbound_session_id,
                     stream! {
                         loop {
-                            let result_opt = read_message(&mut read_stream).await;
+                            let result_opt = read_message(&mut read_stream, max_frame_bytes).await;
                             let result = match result_opt {
+                                Ok(Some(frame)) if is_compressed => {
+                                    decompress_frame(&frame, max_frame_bytes)
+                                }
                                 Ok(Some(data)) => Ok(data),
                                 Ok(None) => break,
-                                Err(error) => Err(error),
+                                Err(error) => Err(SessionError::from_read_message_error(error)),
                             };
                             let is_err = result.is_err();
                             yield result;
@@ -358,7 +433,7 @@ impl ConnectionHandler for Handler {
                 );
             }
             ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
-                protocol: (query, write_stream, protocol_name),
+                protocol: (query, write_stream, protocol_name, is_compressed),
                 info: inbound_session_id,
             }) => {
                 // No need to wake because the swarm guarantees that `poll` will be called after
@@ -372,7 +447,7 @@ impl ConnectionHandler for Handler {
                     }),
                 ));
                 self.id_to_inbound_session
-                    .insert(inbound_session_id, InboundSession::new(write_stream));
+                    .insert(inbound_session_id, InboundSession::new(write_stream, is_compressed));
             }
             ConnectionEvent::DialUpgradeError(DialUpgradeError {
                 info: outbound_session_id,
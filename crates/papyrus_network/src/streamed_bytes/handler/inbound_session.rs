@@ -8,6 +8,7 @@ use futures::future::BoxFuture;
 use futures::io::WriteHalf;
 use futures::{AsyncWriteExt, FutureExt};
 use libp2p::swarm::Stream;
+use papyrus_storage::compression_utils::compress;
 use replace_with::replace_with_or_abort;
 
 use super::super::messages::write_message;
@@ -17,6 +18,7 @@ pub(super) struct InboundSession {
     pending_messages: VecDeque<Bytes>,
     current_task: WriteMessageTask,
     wakers_waiting_for_new_message: Vec<Waker>,
+    compress_data: bool,
 }
 
 enum FinishReason {
@@ -31,11 +33,12 @@ enum WriteMessageTask {
 }
 
 impl InboundSession {
-    pub fn new(write_stream: WriteHalf<Stream>) -> Self {
+    pub fn new(write_stream: WriteHalf<Stream>, compress_data: bool) -> Self {
         Self {
             pending_messages: Default::default(),
             current_task: WriteMessageTask::Waiting(write_stream),
             wakers_waiting_for_new_message: Default::default(),
+            compress_data,
         }
     }
 
@@ -62,12 +65,14 @@ impl InboundSession {
 
     fn handle_waiting(&mut self, cx: &mut Context<'_>) -> Poll<()> {
         if let Some(data) = self.pending_messages.pop_front() {
+            let compress_data = self.compress_data;
             replace_with_or_abort(&mut self.current_task, |current_task| {
                 let WriteMessageTask::Waiting(mut write_stream) = current_task else {
                     panic!("Called handle_waiting while not waiting.");
                 };
                 WriteMessageTask::Running(
                     async move {
+                        let data = if compress_data { compress(&data)? } else { data };
                         write_message(&data, &mut write_stream).await?;
                         Ok(write_stream)
                     }
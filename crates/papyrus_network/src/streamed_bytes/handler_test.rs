@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::pin::Pin;
 use std::sync::atomic::AtomicUsize;
@@ -22,9 +22,18 @@ use libp2p::swarm::{
     StreamUpgradeError,
 };
 use libp2p::PeerId;
+use papyrus_storage::compression_utils::compress;
 
 use super::super::messages::{read_message, write_message};
-use super::super::{Bytes, Config, GenericEvent, InboundSessionId, OutboundSessionId, SessionId};
+use super::super::{
+    Bytes,
+    Config,
+    GenericEvent,
+    InboundSessionId,
+    OutboundSessionId,
+    SessionId,
+    DEFAULT_MAX_FRAME_BYTES,
+};
 use super::{
     Handler,
     HandlerEvent,
@@ -92,7 +101,7 @@ fn simulate_negotiated_inbound_session_from_swarm(
     inbound_session_id: InboundSessionId,
 ) {
     handler.on_connection_event(ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
-        protocol: (query, inbound_stream.split().1, PROTOCOL_NAME.clone()),
+        protocol: (query, inbound_stream.split().1, PROTOCOL_NAME.clone(), false),
         info: inbound_session_id,
     }));
 }
@@ -103,7 +112,10 @@ fn simulate_negotiated_outbound_session_from_swarm(
     outbound_session_id: OutboundSessionId,
 ) {
     handler.on_connection_event(ConnectionEvent::FullyNegotiatedOutbound(
-        FullyNegotiatedOutbound { protocol: outbound_stream.split().0, info: outbound_session_id },
+        FullyNegotiatedOutbound {
+            protocol: (outbound_stream.split().0, false),
+            info: outbound_session_id,
+        },
     ));
 }
 
@@ -224,7 +236,7 @@ async fn read_messages(handler: Handler, stream: &mut Stream, num_messages: usiz
     async fn read_messages_inner(stream: &mut Stream, num_messages: usize) -> Vec<Bytes> {
         let mut result = Vec::new();
         for _ in 0..num_messages {
-            match read_message(&mut *stream).await.unwrap() {
+            match read_message(&mut *stream, DEFAULT_MAX_FRAME_BYTES).await.unwrap() {
                 Some(message) => result.push(message),
                 None => return result,
             }
@@ -352,6 +364,164 @@ async fn process_outbound_session() {
     validate_session_finished_successfully_event(&mut handler, outbound_session_id.into()).await;
 }
 
+#[tokio::test]
+async fn outbound_session_fails_on_too_large_frame() {
+    const MAX_FRAME_BYTES: usize = 2;
+    let mut handler = Handler::new(
+        Config { max_frame_bytes: MAX_FRAME_BYTES, ..Config::get_test_config() },
+        Arc::new(Default::default()),
+        PeerId::random(),
+    );
+
+    let (mut inbound_stream, outbound_stream, _) = get_connected_streams().await;
+    let outbound_session_id = OutboundSessionId { value: 1 };
+
+    simulate_negotiated_outbound_session_from_swarm(
+        &mut handler,
+        outbound_stream,
+        outbound_session_id,
+    );
+
+    let small_data = vec![1u8; MAX_FRAME_BYTES];
+    let large_data = vec![1u8; MAX_FRAME_BYTES + 1];
+    write_message(&small_data, &mut inbound_stream).await.unwrap();
+    write_message(&large_data, &mut inbound_stream).await.unwrap();
+
+    validate_received_data_event(&mut handler, &small_data, outbound_session_id).await;
+    validate_session_failed_event(&mut handler, outbound_session_id.into(), |session_error| {
+        matches!(
+            session_error,
+            SessionError::FrameTooLarge { frame_bytes, max_frame_bytes }
+            if *frame_bytes == MAX_FRAME_BYTES + 1 && *max_frame_bytes == MAX_FRAME_BYTES
+        )
+    })
+    .await;
+    validate_no_events(&mut handler);
+}
+
+#[tokio::test]
+async fn compressed_data_round_trip_between_handlers() {
+    let config = Config { compress_data: true, ..Config::get_test_config() };
+    let mut inbound_handler =
+        Handler::new(config.clone(), Arc::new(Default::default()), PeerId::random());
+    let mut outbound_handler = Handler::new(config, Arc::new(Default::default()), PeerId::random());
+
+    let (inbound_stream, outbound_stream, _) = get_connected_streams().await;
+    let inbound_session_id = InboundSessionId { value: 1 };
+    let outbound_session_id = OutboundSessionId { value: 1 };
+
+    inbound_handler.on_connection_event(ConnectionEvent::FullyNegotiatedInbound(
+        FullyNegotiatedInbound {
+            protocol: (QUERY.clone(), inbound_stream.split().1, PROTOCOL_NAME.clone(), true),
+            info: inbound_session_id,
+        },
+    ));
+    validate_new_inbound_session_event(&mut inbound_handler, &QUERY, inbound_session_id).await;
+    outbound_handler.on_connection_event(ConnectionEvent::FullyNegotiatedOutbound(
+        FullyNegotiatedOutbound {
+            protocol: (outbound_stream.split().0, true),
+            info: outbound_session_id,
+        },
+    ));
+
+    let data = vec![7u8; 1000];
+    simulate_request_to_send_data_from_swarm(
+        &mut inbound_handler,
+        data.clone(),
+        inbound_session_id,
+    );
+
+    let mut fused_inbound_handler = inbound_handler.fuse();
+    select! {
+        _ = validate_received_data_event(&mut outbound_handler, &data, outbound_session_id).fuse()
+            => {},
+        _ = fused_inbound_handler.next() => {
+            panic!("There shouldn't be another event from the handler")
+        }
+    }
+}
+
+#[tokio::test]
+async fn outbound_session_fails_on_frame_that_decompresses_past_max_frame_bytes() {
+    const MAX_FRAME_BYTES: usize = 1000;
+    let mut handler = Handler::new(
+        Config { max_frame_bytes: MAX_FRAME_BYTES, ..Config::get_test_config() },
+        Arc::new(Default::default()),
+        PeerId::random(),
+    );
+
+    let (mut inbound_stream, outbound_stream, _) = get_connected_streams().await;
+    let outbound_session_id = OutboundSessionId { value: 1 };
+    handler.on_connection_event(ConnectionEvent::FullyNegotiatedOutbound(
+        FullyNegotiatedOutbound {
+            protocol: (outbound_stream.split().0, true),
+            info: outbound_session_id,
+        },
+    ));
+
+    // The compressed frame is within the limit, but its decompressed data is far beyond it.
+    let compressed_data = compress(&vec![0u8; 100 * MAX_FRAME_BYTES]).unwrap();
+    assert!(compressed_data.len() <= MAX_FRAME_BYTES);
+    write_message(&compressed_data, &mut inbound_stream).await.unwrap();
+
+    validate_session_failed_event(&mut handler, outbound_session_id.into(), |session_error| {
+        matches!(
+            session_error,
+            SessionError::FrameTooLarge { frame_bytes, max_frame_bytes }
+            if *frame_bytes == MAX_FRAME_BYTES + 1 && *max_frame_bytes == MAX_FRAME_BYTES
+        )
+    })
+    .await;
+    validate_no_events(&mut handler);
+}
+
+#[tokio::test]
+async fn outbound_sessions_buffer_at_most_max_inflight_bytes() {
+    const MAX_INFLIGHT_BYTES: usize = 2;
+    const NUM_MESSAGES_PER_SESSION: usize = 5;
+    let mut handler = Handler::new(
+        Config { max_inflight_bytes: MAX_INFLIGHT_BYTES, ..Config::get_test_config() },
+        Arc::new(Default::default()),
+        PeerId::random(),
+    );
+
+    // Each poll of the handler returns a single event, so with two sessions that send data
+    // constantly, data is buffered in the handler faster than it's passed to the behaviour.
+    let mut inbound_streams = vec![];
+    for value in 0..2 {
+        let (mut inbound_stream, outbound_stream, _) = get_connected_streams().await;
+        simulate_negotiated_outbound_session_from_swarm(
+            &mut handler,
+            outbound_stream,
+            OutboundSessionId { value },
+        );
+        for _ in 0..NUM_MESSAGES_PER_SESSION {
+            write_message(&vec![1u8], &mut inbound_stream).await.unwrap();
+        }
+        inbound_streams.push(inbound_stream);
+    }
+
+    let mut num_received_messages = HashMap::<OutboundSessionId, usize>::new();
+    for _ in 0..(2 * NUM_MESSAGES_PER_SESSION) {
+        let event = handler.next().await.unwrap();
+        let ConnectionHandlerEvent::NotifyBehaviour(RequestToBehaviourEvent::GenerateEvent(
+            GenericEvent::ReceivedData { outbound_session_id, .. },
+        )) = event
+        else {
+            panic!("Expected a ReceivedData event, got {event:?}");
+        };
+        *num_received_messages.entry(outbound_session_id).or_default() += 1;
+        for value in 0..2 {
+            assert!(
+                *handler.outbound_session_id_to_buffered_bytes.get(OutboundSessionId { value })
+                    <= MAX_INFLIGHT_BYTES
+            );
+        }
+    }
+    assert!(num_received_messages.values().all(|num| *num == NUM_MESSAGES_PER_SESSION));
+    validate_no_events(&mut handler);
+}
+
 // Extracting to a function because two closures have different types.
 async fn test_outbound_session_negotiation_failure(
     upgrade_error: StreamUpgradeError<io::Error>,
@@ -505,7 +675,7 @@ async fn inbound_session_dropped() {
     tokio::time::sleep(std::time::Duration::from_millis(10)).await;
 
     // A dropped inbound session will return EOF.
-    assert!(read_message(&mut outbound_stream).await.unwrap().is_none());
+    assert!(read_message(&mut outbound_stream, DEFAULT_MAX_FRAME_BYTES).await.unwrap().is_none());
 
     // Need to sleep to make sure that if we did send a message the stream inside the handle will
     // receive it
@@ -10,7 +10,12 @@ use unsigned_varint::encode::usize_buffer;
 
 use super::Bytes;
 
-pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
+#[derive(thiserror::Error, Debug)]
+#[error("Received data size ({message_len} bytes) exceeds maximum ({max_message_size} bytes)")]
+pub struct MessageTooLargeError {
+    pub message_len: usize,
+    pub max_message_size: usize,
+}
 
 pub async fn write_message<Stream: AsyncWrite + Unpin>(
     message: &Bytes,
@@ -33,18 +38,18 @@ pub async fn write_message_without_length_prefix<Stream: AsyncWrite + Unpin>(
     Ok(())
 }
 
+/// Read a length prefixed message. If the message is longer than `max_message_size`, return an
+/// error wrapping a [`MessageTooLargeError`] without reading the message's content.
 pub async fn read_message<Stream: AsyncRead + Unpin>(
     io: &mut Stream,
+    max_message_size: usize,
 ) -> Result<Option<Bytes>, io::Error> {
     // This code is based on read_length_prefixed from libp2p v0.52 which was erased in v0.53.
     let Some(message_len) = read_usize(io).await? else { return Ok(None) };
-    if message_len > MAX_MESSAGE_SIZE {
+    if message_len > max_message_size {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            format!(
-                "Received data size ({message_len} bytes) exceeds maximum ({MAX_MESSAGE_SIZE} \
-                 bytes)"
-            ),
+            MessageTooLargeError { message_len, max_message_size },
         ));
     }
     let mut buf = vec![0u8; message_len];
@@ -8,7 +8,9 @@ use super::{
     read_message_without_length_prefix,
     write_message,
     write_message_without_length_prefix,
+    MessageTooLargeError,
 };
+use crate::streamed_bytes::DEFAULT_MAX_FRAME_BYTES;
 use crate::test_utils::{dummy_data, get_connected_streams};
 
 #[tokio::test]
@@ -19,7 +21,10 @@ async fn read_write_positive_flow() {
         write_message(message, &mut stream1).await.unwrap();
     }
     for expected_message in &messages {
-        assert_eq!(*expected_message, read_message(&mut stream2).await.unwrap().unwrap());
+        assert_eq!(
+            *expected_message,
+            read_message(&mut stream2, DEFAULT_MAX_FRAME_BYTES).await.unwrap().unwrap()
+        );
     }
 }
 
@@ -37,13 +42,29 @@ async fn read_write_without_length_prefix_positive_flow() {
 async fn read_message_returns_none_when_other_stream_is_closed() {
     let (mut stream1, mut stream2, _) = get_connected_streams().await;
     stream1.close().await.unwrap();
-    assert!(read_message(&mut stream2).await.unwrap().is_none());
+    assert!(read_message(&mut stream2, DEFAULT_MAX_FRAME_BYTES).await.unwrap().is_none());
 }
 
 #[tokio::test]
 async fn read_message_is_pending_when_other_stream_didnt_send() {
     let (_stream1, mut stream2, _) = get_connected_streams().await;
-    assert!(
-        tokio::time::timeout(Duration::from_millis(10), read_message(&mut stream2)).await.is_err()
-    );
+    assert!(tokio::time::timeout(
+        Duration::from_millis(10),
+        read_message(&mut stream2, DEFAULT_MAX_FRAME_BYTES)
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn read_message_fails_when_message_is_too_large() {
+    let (mut stream1, mut stream2, _) = get_connected_streams().await;
+    let message = vec![1u8; 10];
+    write_message(&message, &mut stream1).await.unwrap();
+
+    let error = read_message(&mut stream2, message.len() - 1).await.unwrap_err();
+    let message_too_large_error =
+        error.get_ref().unwrap().downcast_ref::<MessageTooLargeError>().unwrap();
+    assert_eq!(message_too_large_error.message_len, message.len());
+    assert_eq!(message_too_large_error.max_message_size, message.len() - 1);
 }
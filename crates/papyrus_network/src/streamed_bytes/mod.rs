@@ -5,31 +5,55 @@ pub mod protocol;
 
 #[cfg(test)]
 mod flow_test;
+#[cfg(test)]
+mod session_id_test;
 
 use std::time::Duration;
 
 use derive_more::Display;
 use libp2p::swarm::StreamProtocol;
 use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
 
 pub type Bytes = Vec<u8>;
 
-#[derive(Clone, Copy, Debug, Default, Display, Eq, Hash, PartialEq)]
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 1 << 20;
+pub const DEFAULT_DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_CONCURRENT_DIALS: usize = 10;
+pub const DEFAULT_MAX_INFLIGHT_BYTES: usize = 1 << 22;
+
+#[derive(Clone, Copy, Debug, Default, Display, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[display(fmt = "out:{}", value)]
 pub struct OutboundSessionId {
     pub value: usize,
 }
 
-#[derive(Clone, Copy, Debug, Default, Display, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Display, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[display(fmt = "in:{}", value)]
 pub struct InboundSessionId {
     pub value: usize,
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+/// The id of either an inbound or an outbound session. Use it in code that handles sessions of
+/// both directions, and the specific ids where the direction matters.
+#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
 pub enum SessionId {
+    #[display(fmt = "{}", _0)]
     OutboundSessionId(OutboundSessionId),
+    #[display(fmt = "{}", _0)]
     InboundSessionId(InboundSessionId),
 }
 
+impl SessionId {
+    pub fn is_inbound(&self) -> bool {
+        matches!(self, Self::InboundSessionId(_))
+    }
+
+    pub fn is_outbound(&self) -> bool {
+        matches!(self, Self::OutboundSessionId(_))
+    }
+}
+
 impl From<OutboundSessionId> for SessionId {
     fn from(outbound_session_id: OutboundSessionId) -> Self {
         Self::OutboundSessionId(outbound_session_id)
@@ -42,6 +66,28 @@ impl From<InboundSessionId> for SessionId {
     }
 }
 
+impl TryFrom<SessionId> for OutboundSessionId {
+    type Error = SessionId;
+
+    fn try_from(session_id: SessionId) -> Result<Self, Self::Error> {
+        match session_id {
+            SessionId::OutboundSessionId(outbound_session_id) => Ok(outbound_session_id),
+            SessionId::InboundSessionId(_) => Err(session_id),
+        }
+    }
+}
+
+impl TryFrom<SessionId> for InboundSessionId {
+    type Error = SessionId;
+
+    fn try_from(session_id: SessionId) -> Result<Self, Self::Error> {
+        match session_id {
+            SessionId::InboundSessionId(inbound_session_id) => Ok(inbound_session_id),
+            SessionId::OutboundSessionId(_) => Err(session_id),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum GenericEvent<SessionError> {
     NewInboundSession {
@@ -63,6 +109,19 @@ pub enum GenericEvent<SessionError> {
     },
 }
 
+/// Which dial the behaviour drops when its queue of pending events is full. Only dials are dropped,
+/// and the queries that wait for a dropped dial fail once the dial times out. Events of sessions
+/// are never dropped, so they're added even if the queue is full.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum PendingEventsOverflowPolicy {
+    /// Drop the oldest pending dial to make room for the new event. If there's no pending dial and
+    /// the new event is a dial, the new dial is dropped.
+    #[default]
+    DropOldest,
+    /// Drop the new event if it's a dial.
+    RejectNew,
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Config {
     pub session_timeout: Duration,
@@ -70,4 +129,28 @@ pub struct Config {
     // latest is the first (They don't have to appear continuously among the other protocols).
     // TODO(shahak): Sort protocols upon construction by version
     pub supported_inbound_protocols: Vec<StreamProtocol>,
+    // The maximal size of a data message received on an outbound session. A session that receives
+    // a larger message fails and its substream is closed.
+    pub max_frame_bytes: usize,
+    // The maximal number of bytes an outbound session holds in its connection handler that weren't
+    // passed to the behaviour yet. Once reached, the session stops reading from the other peer
+    // until the handler passes its data on. The buffered bytes may exceed this limit by at most
+    // one message. The handler passes data on only when the swarm is polled, so a consumer that
+    // stops polling the swarm while it's slow to read the received data stalls the reads as well.
+    pub max_inflight_bytes: usize,
+    // The maximal time to wait for a connection to a peer that we dialed in order to send it a
+    // query. Queries to the peer fail if the connection isn't established in time.
+    pub dial_timeout: Duration,
+    // The maximal number of peers that are dialed at the same time. Other peers wait for their
+    // turn to be dialed.
+    pub max_concurrent_dials: usize,
+    // The maximal number of events the behaviour holds before they're polled by the swarm. Once
+    // reached, dials are dropped according to the overflow policy. The limit may be exceeded by
+    // events of sessions, which are exempt from it and never dropped. If None, the number of
+    // pending events is unbounded.
+    pub max_pending_events: Option<usize>,
+    pub pending_events_overflow_policy: PendingEventsOverflowPolicy,
+    // Whether to compress the data messages of sessions. Compression is used on a session only if
+    // the other peer supports it as well. Otherwise, the data is sent uncompressed.
+    pub compress_data: bool,
 }
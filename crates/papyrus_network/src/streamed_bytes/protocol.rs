@@ -2,7 +2,7 @@
 #[path = "protocol_test.rs"]
 mod protocol_test;
 
-use std::{io, iter};
+use std::io;
 
 use futures::future::BoxFuture;
 use futures::io::{ReadHalf, WriteHalf};
@@ -13,13 +13,38 @@ use libp2p::swarm::StreamProtocol;
 use super::messages::{read_message_without_length_prefix, write_message_without_length_prefix};
 use super::Bytes;
 
+/// The suffix of the protocol names on which the data messages are compressed with gzip. A peer
+/// that compresses data offers the compressed variant of each protocol first, so peers that don't
+/// support compression negotiate the plain protocol.
+pub const COMPRESSED_PROTOCOL_SUFFIX: &str = "/gzip";
+
+pub fn compressed_protocol_name(protocol_name: &StreamProtocol) -> StreamProtocol {
+    StreamProtocol::try_from_owned(format!(
+        "{}{COMPRESSED_PROTOCOL_SUFFIX}",
+        protocol_name.as_ref()
+    ))
+    .expect("Adding a suffix to a valid protocol name should result in a valid protocol name")
+}
+
+fn protocol_names_to_offer(
+    protocol_name: &StreamProtocol,
+    compress_data: bool,
+) -> Vec<StreamProtocol> {
+    if compress_data {
+        vec![compressed_protocol_name(protocol_name), protocol_name.clone()]
+    } else {
+        vec![protocol_name.clone()]
+    }
+}
+
 pub struct InboundProtocol {
     supported_protocols: Vec<StreamProtocol>,
+    compress_data: bool,
 }
 
 impl InboundProtocol {
-    pub fn new(supported_protocols: Vec<StreamProtocol>) -> Self {
-        Self { supported_protocols }
+    pub fn new(supported_protocols: Vec<StreamProtocol>, compress_data: bool) -> Self {
+        Self { supported_protocols, compress_data }
     }
 }
 
@@ -28,7 +53,10 @@ impl UpgradeInfo for InboundProtocol {
     type InfoIter = Vec<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        self.supported_protocols.clone()
+        self.supported_protocols
+            .iter()
+            .flat_map(|protocol_name| protocol_names_to_offer(protocol_name, self.compress_data))
+            .collect()
     }
 }
 
@@ -36,15 +64,28 @@ impl<Stream> InboundUpgrade<Stream> for InboundProtocol
 where
     Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    type Output = (Bytes, WriteHalf<Stream>, StreamProtocol);
+    // The protocol name in the output is the uncompressed one, along with whether the data should
+    // be compressed.
+    type Output = (Bytes, WriteHalf<Stream>, StreamProtocol, bool);
     type Error = io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
     fn upgrade_inbound(self, stream: Stream, protocol_name: Self::Info) -> Self::Future {
+        let uncompressed_protocol_name = if self.compress_data {
+            self.supported_protocols.into_iter().find(|supported_protocol| {
+                compressed_protocol_name(supported_protocol) == protocol_name
+            })
+        } else {
+            None
+        };
+        let (protocol_name, is_compressed) = match uncompressed_protocol_name {
+            Some(uncompressed_protocol_name) => (uncompressed_protocol_name, true),
+            None => (protocol_name, false),
+        };
         async move {
             let (read_half, write_half) = stream.split();
             let request = read_message_without_length_prefix(read_half).await?;
-            Ok((request, write_half, protocol_name))
+            Ok((request, write_half, protocol_name, is_compressed))
         }
         .boxed()
     }
@@ -54,14 +95,15 @@ where
 pub struct OutboundProtocol {
     pub query: Bytes,
     pub protocol_name: StreamProtocol,
+    pub compress_data: bool,
 }
 
 impl UpgradeInfo for OutboundProtocol {
     type Info = StreamProtocol;
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = Vec<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(self.protocol_name.clone())
+        protocol_names_to_offer(&self.protocol_name, self.compress_data)
     }
 }
 
@@ -69,15 +111,17 @@ impl<Stream> OutboundUpgrade<Stream> for OutboundProtocol
 where
     Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    type Output = ReadHalf<Stream>;
+    // The stream to read the data from, along with whether the data is compressed.
+    type Output = (ReadHalf<Stream>, bool);
     type Error = io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
-    fn upgrade_outbound(self, stream: Stream, _: Self::Info) -> Self::Future {
+    fn upgrade_outbound(self, stream: Stream, protocol_name: Self::Info) -> Self::Future {
+        let is_compressed = protocol_name != self.protocol_name;
         async move {
             let (read_half, write_half) = stream.split();
             write_message_without_length_prefix(&self.query, write_half).await?;
-            Ok(read_half)
+            Ok((read_half, is_compressed))
         }
         .boxed()
     }
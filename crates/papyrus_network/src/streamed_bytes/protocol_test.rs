@@ -4,48 +4,121 @@ use libp2p::swarm::StreamProtocol;
 use pretty_assertions::assert_eq;
 
 use super::super::messages::{read_message, write_message};
-use super::{InboundProtocol, OutboundProtocol};
+use super::super::DEFAULT_MAX_FRAME_BYTES;
+use super::{compressed_protocol_name, InboundProtocol, OutboundProtocol};
 use crate::test_utils::{dummy_data, get_connected_streams};
 
 pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/example/1.0.0");
 
 #[test]
 fn outbound_protocol_info() {
-    let outbound_protocol =
-        OutboundProtocol { query: Default::default(), protocol_name: PROTOCOL_NAME };
-    assert_eq!(outbound_protocol.protocol_info().collect::<Vec<_>>(), vec![PROTOCOL_NAME]);
+    let outbound_protocol = OutboundProtocol {
+        query: Default::default(),
+        protocol_name: PROTOCOL_NAME,
+        compress_data: false,
+    };
+    assert_eq!(outbound_protocol.protocol_info(), vec![PROTOCOL_NAME]);
+}
+
+#[test]
+fn outbound_protocol_info_with_compression() {
+    let outbound_protocol = OutboundProtocol {
+        query: Default::default(),
+        protocol_name: PROTOCOL_NAME,
+        compress_data: true,
+    };
+    assert_eq!(
+        outbound_protocol.protocol_info(),
+        vec![StreamProtocol::new("/example/1.0.0/gzip"), PROTOCOL_NAME]
+    );
 }
 
 #[test]
 fn inbound_protocol_info() {
     let protocol_names = vec![PROTOCOL_NAME, StreamProtocol::new("/example/2.0.0")];
-    let inbound_protocol = InboundProtocol::new(protocol_names.clone());
+    let inbound_protocol = InboundProtocol::new(protocol_names.clone(), false);
     assert_eq!(inbound_protocol.protocol_info(), protocol_names);
 }
 
+#[test]
+fn inbound_protocol_info_with_compression() {
+    let other_protocol_name = StreamProtocol::new("/example/2.0.0");
+    let inbound_protocol =
+        InboundProtocol::new(vec![PROTOCOL_NAME, other_protocol_name.clone()], true);
+    assert_eq!(
+        inbound_protocol.protocol_info(),
+        vec![
+            compressed_protocol_name(&PROTOCOL_NAME),
+            PROTOCOL_NAME,
+            compressed_protocol_name(&other_protocol_name),
+            other_protocol_name,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn compressed_protocol_negotiated() {
+    let (inbound_stream, outbound_stream, _) = get_connected_streams().await;
+
+    let query = vec![1u8, 2u8, 3u8];
+    let outbound_protocol = OutboundProtocol {
+        query: query.clone(),
+        protocol_name: PROTOCOL_NAME,
+        compress_data: true,
+    };
+    let inbound_protocol = InboundProtocol::new(vec![PROTOCOL_NAME], true);
+    let negotiated_protocol_name = compressed_protocol_name(&PROTOCOL_NAME);
+
+    tokio::join!(
+        async {
+            let (received_query, _stream, protocol_name, is_compressed) = inbound_protocol
+                .upgrade_inbound(inbound_stream, negotiated_protocol_name.clone())
+                .await
+                .unwrap();
+            assert_eq!(query, received_query);
+            assert_eq!(protocol_name, PROTOCOL_NAME);
+            assert!(is_compressed);
+        },
+        async {
+            let (_stream, is_compressed) = outbound_protocol
+                .upgrade_outbound(outbound_stream, negotiated_protocol_name.clone())
+                .await
+                .unwrap();
+            assert!(is_compressed);
+        }
+    );
+}
+
 #[tokio::test]
 async fn positive_flow() {
     let (inbound_stream, outbound_stream, _) = get_connected_streams().await;
 
     let query = vec![1u8, 2u8, 3u8];
-    let outbound_protocol = OutboundProtocol { query: query.clone(), protocol_name: PROTOCOL_NAME };
-    let inbound_protocol = InboundProtocol::new(vec![PROTOCOL_NAME]);
+    let outbound_protocol = OutboundProtocol {
+        query: query.clone(),
+        protocol_name: PROTOCOL_NAME,
+        compress_data: false,
+    };
+    let inbound_protocol = InboundProtocol::new(vec![PROTOCOL_NAME], false);
 
     tokio::join!(
         async move {
-            let (received_query, mut stream, protocol_name) =
+            let (received_query, mut stream, protocol_name, is_compressed) =
                 inbound_protocol.upgrade_inbound(inbound_stream, PROTOCOL_NAME).await.unwrap();
             assert_eq!(query, received_query);
             assert_eq!(protocol_name, PROTOCOL_NAME);
+            assert!(!is_compressed);
             for response in dummy_data() {
                 write_message(&response, &mut stream).await.unwrap();
             }
         },
         async move {
-            let mut stream =
+            let (mut stream, is_compressed) =
                 outbound_protocol.upgrade_outbound(outbound_stream, PROTOCOL_NAME).await.unwrap();
+            assert!(!is_compressed);
             for expected_response in dummy_data() {
-                let response = read_message(&mut stream).await.unwrap().unwrap();
+                let response =
+                    read_message(&mut stream, DEFAULT_MAX_FRAME_BYTES).await.unwrap().unwrap();
                 assert_eq!(response, expected_response);
             }
         }
@@ -55,7 +128,8 @@ async fn positive_flow() {
 #[tokio::test]
 async fn inbound_dropped() {
     let (inbound_stream, outbound_stream, _) = get_connected_streams().await;
-    let outbound_protocol = OutboundProtocol { query: vec![0u8], protocol_name: PROTOCOL_NAME };
+    let outbound_protocol =
+        OutboundProtocol { query: vec![0u8], protocol_name: PROTOCOL_NAME, compress_data: false };
 
     drop(inbound_stream);
 
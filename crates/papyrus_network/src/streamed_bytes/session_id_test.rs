@@ -0,0 +1,47 @@
+use super::{InboundSessionId, OutboundSessionId, SessionId};
+
+#[test]
+fn session_id_display() {
+    assert_eq!(OutboundSessionId { value: 3 }.to_string(), "out:3");
+    assert_eq!(InboundSessionId { value: 5 }.to_string(), "in:5");
+    assert_eq!(SessionId::from(OutboundSessionId { value: 3 }).to_string(), "out:3");
+    assert_eq!(SessionId::from(InboundSessionId { value: 5 }).to_string(), "in:5");
+}
+
+#[test]
+fn session_id_direction() {
+    let outbound_session_id = SessionId::from(OutboundSessionId { value: 0 });
+    assert!(outbound_session_id.is_outbound());
+    assert!(!outbound_session_id.is_inbound());
+
+    let inbound_session_id = SessionId::from(InboundSessionId { value: 0 });
+    assert!(inbound_session_id.is_inbound());
+    assert!(!inbound_session_id.is_outbound());
+}
+
+#[test]
+fn session_id_round_trip() {
+    let outbound_session_id = OutboundSessionId { value: 7 };
+    let session_id = SessionId::from(outbound_session_id);
+    assert_eq!(OutboundSessionId::try_from(session_id), Ok(outbound_session_id));
+    assert_eq!(InboundSessionId::try_from(session_id), Err(session_id));
+
+    let inbound_session_id = InboundSessionId { value: 7 };
+    let session_id = SessionId::from(inbound_session_id);
+    assert_eq!(InboundSessionId::try_from(session_id), Ok(inbound_session_id));
+    assert_eq!(OutboundSessionId::try_from(session_id), Err(session_id));
+}
+
+#[test]
+fn session_id_serde_round_trip() {
+    let outbound_session_id = OutboundSessionId { value: 3 };
+    let serialized = serde_json::to_string(&outbound_session_id).unwrap();
+    assert_eq!(
+        serde_json::from_str::<OutboundSessionId>(&serialized).unwrap(),
+        outbound_session_id
+    );
+
+    let inbound_session_id = InboundSessionId { value: 5 };
+    let serialized = serde_json::to_string(&inbound_session_id).unwrap();
+    assert_eq!(serde_json::from_str::<InboundSessionId>(&serialized).unwrap(), inbound_session_id);
+}
@@ -9,13 +9,29 @@ use std::task::{Context, Poll};
 use std::time::Duration;
 
 use futures::stream::{Stream as StreamTrait, StreamExt};
+use lazy_static::lazy_static;
 use libp2p::swarm::{NetworkBehaviour, StreamProtocol, Swarm, SwarmEvent};
 use libp2p::{PeerId, Stream};
 use libp2p_swarm_test::SwarmExt;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 use tokio::task::JoinHandle;
 use tokio_stream::StreamExt as TokioStreamExt;
 
-use crate::streamed_bytes::Bytes;
+use crate::streamed_bytes::{
+    Bytes,
+    DEFAULT_DIAL_TIMEOUT,
+    DEFAULT_MAX_CONCURRENT_DIALS,
+    DEFAULT_MAX_FRAME_BYTES,
+    DEFAULT_MAX_INFLIGHT_BYTES,
+};
+
+lazy_static! {
+    // Only a single metrics recorder can be installed in a process, so all the tests that check
+    // metrics share it. Since the metrics are global, each test should check metrics that no other
+    // test updates (e.g. with a protocol label that no other test uses).
+    pub(crate) static ref PROMETHEUS_HANDLE: PrometheusHandle =
+        PrometheusBuilder::new().install_recorder().unwrap();
+}
 
 /// Create two streams that are connected to each other. Return them and a join handle for a thread
 /// that will perform the sends between the streams (this thread will run forever so it shouldn't
@@ -30,7 +46,11 @@ pub(crate) async fn get_connected_streams() -> (Stream, Stream, JoinHandle<()>)
 
     let merged_swarm = swarm1.merge(swarm2);
     let mut filtered_swarm = TokioStreamExt::filter_map(merged_swarm, |event| {
-        if let SwarmEvent::Behaviour(stream) = event { Some(stream) } else { None }
+        if let SwarmEvent::Behaviour(stream) = event {
+            Some(stream)
+        } else {
+            None
+        }
     });
     (
         TokioStreamExt::next(&mut filtered_swarm).await.unwrap(),
@@ -50,6 +70,13 @@ impl crate::streamed_bytes::Config {
         Self {
             session_timeout: Duration::MAX,
             supported_inbound_protocols: vec![StreamProtocol::new("/")],
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_inflight_bytes: DEFAULT_MAX_INFLIGHT_BYTES,
+            dial_timeout: DEFAULT_DIAL_TIMEOUT,
+            max_concurrent_dials: DEFAULT_MAX_CONCURRENT_DIALS,
+            max_pending_events: None,
+            pending_events_overflow_policy: Default::default(),
+            compress_data: false,
         }
     }
 }
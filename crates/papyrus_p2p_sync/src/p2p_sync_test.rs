@@ -35,7 +35,10 @@ fn setup() -> (P2PSync, StorageReader, Receiver<Query>, Sender<Option<SignedBloc
         storage_reader.clone(),
         storage_writer,
         query_sender,
-        ResponseReceivers { signed_headers_receiver: signed_headers_receiver.boxed() },
+        ResponseReceivers {
+            signed_headers_receiver: signed_headers_receiver.boxed(),
+            session_events_receiver: futures::stream::pending().boxed(),
+        },
     );
     (p2p_sync, storage_reader, query_receiver, signed_headers_sender)
 }
@@ -5,10 +5,70 @@ mod compression_utils_test;
 use std::io::Read;
 
 use flate2::bufread::{GzDecoder, GzEncoder};
-use flate2::Compression;
+use flate2::{Compression, Crc};
 
 use crate::db::serialization::{StorageSerde, StorageSerdeError};
 
+// The first two bytes of every gzip stream. Data compressed by `compress`, which isn't tagged with
+// its algorithm, is detected by them.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// Set on the algorithm tag of data that's followed by a CRC32 checksum of the compressed bytes.
+const CHECKSUM_FLAG: u8 = 0x80;
+const CHECKSUM_BYTES: usize = 4;
+
+/// The algorithms that data can be compressed with by [`compress_tagged`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompressionAlgorithm {
+    /// Gzip, which data compressed by [`compress`] uses.
+    Gzip = 1,
+    /// Zstandard.
+    Zstd = 2,
+}
+
+impl TryFrom<u8> for CompressionAlgorithm {
+    type Error = CompressionError;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        match tag {
+            1 => Ok(Self::Gzip),
+            2 => Ok(Self::Zstd),
+            _ => Err(CompressionError::UnknownAlgorithmTag(tag)),
+        }
+    }
+}
+
+/// The algorithm and level to compress data with. The level is interpreted by the algorithm:
+/// 0-9 for gzip and 1-22 for zstd.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CompressionConfig {
+    /// The algorithm to compress with.
+    pub algorithm: CompressionAlgorithm,
+    /// The compression level of the algorithm.
+    pub level: u32,
+    /// Whether to store a checksum of the compressed data with it, so that corrupted data is
+    /// detected before it's decompressed.
+    pub checksum: bool,
+}
+
+impl CompressionConfig {
+    /// Returns a config of the given algorithm and level. The checksum is opt-in, since data
+    /// compressed with it can't be read by versions that don't know about it.
+    pub fn new(algorithm: CompressionAlgorithm, level: u32, checksum: bool) -> Self {
+        Self { algorithm, level, checksum }
+    }
+}
+
+#[allow(missing_docs)]
+#[derive(thiserror::Error, Debug)]
+pub enum CompressionError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Compressed data is tagged with an unknown compression algorithm {0}.")]
+    UnknownAlgorithmTag(u8),
+    #[error("Compressed data has checksum {actual} instead of its stored checksum {expected}.")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
 // TODO: consider changing the compression hyperparameters: compression level and algorithm.
 
 /// Returns the compressed data in a vector.
@@ -19,7 +79,22 @@ use crate::db::serialization::{StorageSerde, StorageSerdeError};
 /// # Errors
 /// Returns [`std::io::Error`] if any read error is encountered.
 pub fn compress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = GzEncoder::new(data, Compression::default());
+    compress_with_level(data, Compression::default())
+}
+
+/// Returns the data compressed with the given compression level in a vector.
+///
+/// # Arguments
+/// * data - bytes to compress.
+/// * compression - the compression level to use.
+///
+/// # Errors
+/// Returns [`std::io::Error`] if any read error is encountered.
+pub fn compress_with_level(
+    data: &[u8],
+    compression: Compression,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = GzEncoder::new(data, compression);
     let mut compressed_data = Vec::new();
     encoder.read_to_end(&mut compressed_data)?;
     Ok(compressed_data)
@@ -44,7 +119,8 @@ pub fn serialize_and_compress(object: &impl StorageSerde) -> Result<Vec<u8>, Sto
 /// * data - bytes to decompress.
 ///
 /// # Errors
-/// Returns [`std::io::Error`] if any read error is encountered.
+/// Returns [`std::io::Error`] if any read error is encountered, or if the data is corrupted and
+/// doesn't match the CRC32 checksum that gzip stores with it.
 pub fn decompress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     let mut decoder = GzDecoder::new(data);
     let mut uncompressed = Vec::new();
@@ -52,6 +128,91 @@ pub fn decompress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     Ok(uncompressed)
 }
 
+/// Returns the data compressed according to the given config, prefixed with a tag of the
+/// compression algorithm so that [`decompress_tagged`] can detect it. If the config has a checksum,
+/// the tag is followed by a CRC32 checksum of the compressed data.
+///
+/// # Arguments
+/// * data - bytes to compress.
+/// * config - the compression algorithm and level to use.
+///
+/// # Errors
+/// Returns [`std::io::Error`] if any read error is encountered.
+pub fn compress_tagged(data: &[u8], config: CompressionConfig) -> Result<Vec<u8>, std::io::Error> {
+    let compressed_data = match config.algorithm {
+        CompressionAlgorithm::Gzip => compress_with_level(data, Compression::new(config.level))?,
+        CompressionAlgorithm::Zstd => {
+            let level = i32::try_from(config.level).expect("zstd compression level is at most 22");
+            zstd::stream::encode_all(data, level)?
+        }
+    };
+    let mut tagged_data = Vec::with_capacity(compressed_data.len() + 1 + CHECKSUM_BYTES);
+    if config.checksum {
+        tagged_data.push(config.algorithm as u8 | CHECKSUM_FLAG);
+        tagged_data.extend(crc32(&compressed_data).to_le_bytes());
+    } else {
+        tagged_data.push(config.algorithm as u8);
+    }
+    tagged_data.extend(compressed_data);
+    Ok(tagged_data)
+}
+
+/// Decompress data that was compressed by [`compress_tagged`] with the algorithm it's tagged with.
+/// Data that was compressed by [`compress`], before data was tagged, is decompressed as gzip.
+///
+/// # Arguments
+/// * data - bytes to decompress.
+///
+/// # Errors
+/// Returns [`CompressionError`] if any read error is encountered, if the data is tagged with an
+/// unknown algorithm or if the data doesn't match its checksum.
+pub fn decompress_tagged(data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    if data.starts_with(&GZIP_MAGIC) {
+        return Ok(decompress(data)?);
+    }
+    let Some((tag, mut compressed_data)) = data.split_first() else {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    };
+    if tag & CHECKSUM_FLAG != 0 {
+        if compressed_data.len() < CHECKSUM_BYTES {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let (checksum, checksummed_data) = compressed_data.split_at(CHECKSUM_BYTES);
+        let expected =
+            u32::from_le_bytes(checksum.try_into().expect("checksum should be 4 bytes long"));
+        let actual = crc32(checksummed_data);
+        if actual != expected {
+            return Err(CompressionError::ChecksumMismatch { expected, actual });
+        }
+        compressed_data = checksummed_data;
+    }
+    let uncompressed = match CompressionAlgorithm::try_from(tag & !CHECKSUM_FLAG)? {
+        CompressionAlgorithm::Gzip => decompress(compressed_data)?,
+        CompressionAlgorithm::Zstd => zstd::stream::decode_all(compressed_data)?,
+    };
+    Ok(uncompressed)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Decompress data with the algorithm it was compressed with and compress it again according to
+/// the given config. Used when migrating stored data to a different compression algorithm or
+/// level.
+///
+/// # Arguments
+/// * data - compressed bytes to recompress, either tagged or compressed by [`compress`].
+/// * config - the compression algorithm and level to use for the new data.
+///
+/// # Errors
+/// Returns [`CompressionError`] if the data can't be decompressed or compressed again.
+pub fn recompress(data: &[u8], config: CompressionConfig) -> Result<Vec<u8>, CompressionError> {
+    Ok(compress_tagged(decompress_tagged(data)?.as_slice(), config)?)
+}
+
 /// Decompress a vector directly from a reader.
 /// In case of successful decompression, the vector will be returned; otherwise, None.
 ///
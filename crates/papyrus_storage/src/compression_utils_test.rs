@@ -1,8 +1,22 @@
+use assert_matches::assert_matches;
+use flate2::Compression;
 use pretty_assertions::assert_eq;
 use starknet_api::deprecated_contract_class::Program;
 use test_utils::read_json_file;
 
-use super::{compress, decompress, decompress_from_reader, serialize_and_compress};
+use super::{
+    compress,
+    compress_tagged,
+    compress_with_level,
+    decompress,
+    decompress_from_reader,
+    decompress_tagged,
+    recompress,
+    serialize_and_compress,
+    CompressionAlgorithm,
+    CompressionConfig,
+    CompressionError,
+};
 use crate::db::serialization::StorageSerde;
 
 #[test]
@@ -23,3 +37,66 @@ fn object_compression() {
     let restored_program = Program::deserialize_from(&mut decompressed.as_slice()).unwrap();
     assert_eq!(program, restored_program);
 }
+
+#[test]
+fn bytes_recompression() {
+    let bytes = vec![30, 5, 23, 12, 47];
+    let gzip_config = CompressionConfig::new(CompressionAlgorithm::Gzip, 6, false);
+    let zstd_config = CompressionConfig::new(CompressionAlgorithm::Zstd, 3, false);
+
+    // Data compressed before it was tagged is recompressed as well.
+    let compressed = compress_with_level(bytes.as_slice(), Compression::fast()).unwrap();
+    let recompressed = recompress(compressed.as_slice(), zstd_config).unwrap();
+    assert_eq!(recompressed, compress_tagged(bytes.as_slice(), zstd_config).unwrap());
+    assert_eq!(decompress_tagged(recompressed.as_slice()).unwrap(), bytes);
+
+    let compressed = compress_tagged(bytes.as_slice(), gzip_config).unwrap();
+    let recompressed = recompress(compressed.as_slice(), zstd_config).unwrap();
+    assert_eq!(recompressed[0], CompressionAlgorithm::Zstd as u8);
+    assert_eq!(decompress_tagged(recompressed.as_slice()).unwrap(), bytes);
+
+    let recompressed_back = recompress(recompressed.as_slice(), gzip_config).unwrap();
+    assert_eq!(recompressed_back, compressed);
+}
+
+#[test]
+fn decompressing_unknown_algorithm_fails() {
+    let data = vec![0, 30, 5, 23, 12, 47];
+    assert_matches!(
+        decompress_tagged(data.as_slice()),
+        Err(CompressionError::UnknownAlgorithmTag(0))
+    );
+}
+
+#[test]
+fn corrupted_data_decompression_fails() {
+    let bytes = vec![30, 5, 23, 12, 47];
+    let mut compressed = compress(bytes.as_slice()).unwrap();
+    // The gzip trailer consists of the CRC32 of the uncompressed data followed by its size.
+    let crc_index = compressed.len() - 8;
+    compressed[crc_index] ^= 1;
+    decompress(compressed.as_slice()).unwrap_err();
+}
+
+#[test]
+fn checksummed_data_decompression() {
+    let bytes = vec![30, 5, 23, 12, 47];
+    for algorithm in [CompressionAlgorithm::Gzip, CompressionAlgorithm::Zstd] {
+        let config = CompressionConfig::new(algorithm, 3, true);
+        let compressed = compress_tagged(bytes.as_slice(), config).unwrap();
+        assert_eq!(decompress_tagged(compressed.as_slice()).unwrap(), bytes);
+    }
+}
+
+#[test]
+fn corrupted_checksummed_data_fails_on_checksum() {
+    let bytes = vec![30, 5, 23, 12, 47];
+    let config = CompressionConfig::new(CompressionAlgorithm::Zstd, 3, true);
+    let mut compressed = compress_tagged(bytes.as_slice(), config).unwrap();
+    let last_index = compressed.len() - 1;
+    compressed[last_index] ^= 1;
+    assert_matches!(
+        decompress_tagged(compressed.as_slice()),
+        Err(CompressionError::ChecksumMismatch { .. })
+    );
+}